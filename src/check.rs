@@ -0,0 +1,162 @@
+use crate::expression::inline_expressions;
+use crate::{Expression, Knot, KnotEnding, Span, Statement, StatementKind, Story};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Problems with a story that parsed, but doesn't make sense.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StoryError {
+    /// a `~ temp` declared in one knot, and used in another
+    TempOutOfScope {
+        name: String,
+        declared_in: String,
+        used_in: String,
+    },
+    /// a `~ temp` with the same name as a `VAR`
+    TempShadowsGlobal { name: String, knot: String },
+    /// two knots with the same title; diverts go to the first one
+    DuplicateKnot {
+        title: String,
+        first: Span,
+        second: Span,
+    },
+}
+
+/// Things that are allowed, but probably not what the writer meant.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StoryWarning {
+    /// a knot with no choices or divert at the end, so the flow stops there
+    LooseEnd { knot: String },
+}
+
+/// Resolves every variable a knot uses, first against the knot's own temps,
+/// and then against the story's globals.
+pub fn check_story(story: &Story) -> Vec<StoryError> {
+    // by index rather than title, so knots that share a title keep their own temps
+    let temps_by_knot: Vec<BTreeSet<&str>> = story
+        .knots
+        .iter()
+        .map(|knot| temps(knot).collect())
+        .collect();
+
+    let mut errors = vec![];
+
+    let mut first_knots: BTreeMap<&str, &Knot> = BTreeMap::new();
+    for knot in &story.knots {
+        match first_knots.get(knot.title.as_str()) {
+            Some(first) => errors.push(StoryError::DuplicateKnot {
+                title: knot.title.clone(),
+                first: first.span,
+                second: knot.span,
+            }),
+            None => {
+                first_knots.insert(&knot.title, knot);
+            }
+        }
+    }
+
+    for (knot, temps) in story.knots.iter().zip(&temps_by_knot) {
+        for &name in temps {
+            if story.variables.contains_key(name) {
+                errors.push(StoryError::TempShadowsGlobal {
+                    name: name.to_string(),
+                    knot: knot.title.clone(),
+                });
+            }
+        }
+
+        let mut reported = BTreeSet::new();
+        for name in variables_used(knot) {
+            let name = name.as_str();
+            if temps.contains(name) || story.variables.contains_key(name) {
+                continue;
+            }
+
+            let declared_in = story
+                .knots
+                .iter()
+                .zip(&temps_by_knot)
+                .find(|(_, other_temps)| other_temps.contains(name))
+                .map(|(other, _)| other.title.as_str());
+
+            if let Some(declared_in) = declared_in {
+                if reported.insert(name.to_string()) {
+                    errors.push(StoryError::TempOutOfScope {
+                        name: name.to_string(),
+                        declared_in: declared_in.to_string(),
+                        used_in: knot.title.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+pub fn story_warnings(story: &Story) -> Vec<StoryWarning> {
+    story
+        .knots
+        .iter()
+        // a function returns once its content runs out
+        .filter(|knot| knot.ending == KnotEnding::IMPLICIT && !knot.is_function)
+        .map(|knot| StoryWarning::LooseEnd {
+            knot: knot.title.clone(),
+        })
+        .collect()
+}
+
+fn all_statements(knot: &Knot) -> impl Iterator<Item = &Statement> {
+    let choice_statements = match &knot.ending {
+        KnotEnding::CHOICES(choices) => choices
+            .iter()
+            .flat_map(|choice| choice.statements.iter())
+            .collect(),
+        KnotEnding::DIVERT(_) | KnotEnding::IMPLICIT => vec![],
+    };
+
+    knot.statements.iter().chain(choice_statements)
+}
+
+/// A knot's parameters, and the `~ temp`s it declares
+pub(crate) fn temps(knot: &Knot) -> impl Iterator<Item = &str> {
+    let declared = all_statements(knot).filter_map(|statement| match &statement.kind {
+        StatementKind::TempDeclaration(name, _) => Some(name.as_str()),
+        _ => None,
+    });
+    knot.parameters.iter().map(String::as_str).chain(declared)
+}
+
+/// Every variable read or assigned in the knot, including inside `{}` in its text.
+fn variables_used(knot: &Knot) -> Vec<String> {
+    let mut expressions: Vec<Expression> = vec![];
+    let mut names = vec![];
+
+    for statement in all_statements(knot) {
+        match &statement.kind {
+            StatementKind::TempDeclaration(_, value) | StatementKind::Expression(value) => {
+                expressions.push(value.clone())
+            }
+            StatementKind::Assignment(name, value)
+            | StatementKind::AddAssignment(name, value)
+            | StatementKind::SubtractAssignment(name, value) => {
+                names.push(name.clone());
+                expressions.push(value.clone());
+            }
+            StatementKind::Return(value) => expressions.extend(value.clone()),
+        }
+    }
+
+    let mut texts: Vec<&str> = knot.dialog_lines.iter().map(|l| l.text.as_str()).collect();
+    if let KnotEnding::CHOICES(choices) = &knot.ending {
+        for choice in choices {
+            texts.push(&choice.text);
+            texts.extend(choice.dialog_lines.iter().map(|l| l.text.as_str()));
+        }
+    }
+    expressions.extend(texts.into_iter().flat_map(inline_expressions));
+
+    for expression in &expressions {
+        expression.for_each_variable(&mut |name| names.push(name.to_string()));
+    }
+    names
+}
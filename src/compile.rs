@@ -0,0 +1,405 @@
+use crate::check::temps;
+use crate::expression::parse_inline_expression;
+use crate::visit::{walk_choice, walk_dialog_line, Visit};
+use crate::{
+    Choice, DialogLine, Divert, Expression, Knot, KnotEnding, Statement, StatementKind, Story, Tag,
+    UnaryOperator,
+};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+
+/// The version of ink's runtime format that `compile_story` writes
+pub const INK_VERSION: u32 = 21;
+
+/// Compiles a story to the JSON that ink's runtimes (like ink-unity and inkjs) load.
+///
+/// The story starts at its first knot, and the top of a parsed story is a knot
+/// called `INTRO` like any other, so that it can be diverted to. Only `{x}` is
+/// compiled inside text; other `{}` (like conditional text) are written out as they are.
+pub fn compile_story(story: &Story) -> String {
+    story_json(story).to_string()
+}
+
+pub(crate) fn story_json(story: &Story) -> Value {
+    let turns_counted = turns_counted(story);
+    let mut named = Map::new();
+    let mut start = vec![];
+    for tag in &story.global_tags {
+        push_tag(tag, &mut start);
+    }
+
+    for knot in &story.knots {
+        // diverts go to the first knot with a title
+        if named.contains_key(&knot.title) {
+            continue;
+        }
+        let compiler = KnotCompiler::new(story, knot);
+        let mut content = compiler.content(&knot.title);
+        // 0x1 counts visits, for read counts, and 0x2 counts turns, for `TURNS_SINCE`
+        let flags = if turns_counted.contains(&knot.title) {
+            0x3
+        } else {
+            0x1
+        };
+        content.push(compiler.named_content(flags));
+        named.insert(knot.title.clone(), Value::Array(content));
+    }
+    if let Some(first) = story.knots.first() {
+        start.push(json!({ "->": first.title }));
+    }
+    start.push(Value::Null);
+
+    if !story.variables.is_empty() {
+        let compiler = ExpressionCompiler {
+            story,
+            temps: vec![],
+        };
+        let mut declarations = vec![json!("ev")];
+        for (name, value) in &story.variables {
+            compiler.expression(value, &mut declarations);
+            declarations.push(json!({ "VAR=": name }));
+        }
+        declarations.extend(vec![json!("/ev"), json!("end"), Value::Null]);
+        named.insert("global decl".to_string(), Value::Array(declarations));
+    }
+
+    json!({
+        "inkVersion": INK_VERSION,
+        "root": [Value::Array(start), "done", Value::Object(named)],
+        "listDefs": {},
+    })
+}
+
+/// Resolves names the way the runtime will: temps and globals are variables,
+/// and any other knot title is that knot's read count.
+struct ExpressionCompiler<'a> {
+    story: &'a Story,
+    temps: Vec<&'a str>,
+}
+
+struct KnotCompiler<'a> {
+    knot: &'a Knot,
+    expressions: ExpressionCompiler<'a>,
+}
+
+impl<'a> KnotCompiler<'a> {
+    fn new(story: &'a Story, knot: &'a Knot) -> Self {
+        KnotCompiler {
+            knot,
+            expressions: ExpressionCompiler {
+                story,
+                temps: temps(knot).collect(),
+            },
+        }
+    }
+
+    /// Everything in the knot's container, except for its named content.
+    /// `path` is where the container is, for the choices to find their own containers.
+    fn content(&self, path: &str) -> Vec<Value> {
+        let mut out = vec![];
+        // arguments are on the evaluation stack, last one on top
+        for parameter in self.knot.parameters.iter().rev() {
+            out.push(json!({ "temp=": parameter }));
+        }
+        for tag in &self.knot.tags {
+            push_tag(tag, &mut out);
+        }
+        self.lines(&self.knot.dialog_lines, &self.knot.statements, &mut out);
+
+        match &self.knot.ending {
+            KnotEnding::CHOICES(choices) => {
+                for (index, choice) in choices.iter().enumerate() {
+                    out.push(json!("ev"));
+                    out.push(json!("str"));
+                    self.expressions.text(&choice.text, &mut out);
+                    out.push(json!("/str"));
+                    out.push(json!("/ev"));
+                    // 0x4: the choice's text is on the evaluation stack.
+                    // Choices are all sticky (`+`), so they aren't once-only.
+                    out.push(json!({ "*": format!("{}.c-{}", path, index), "flg": 4 }));
+                }
+            }
+            KnotEnding::DIVERT(divert) => out.push(divert_json(divert)),
+            KnotEnding::IMPLICIT if self.knot.is_function => self
+                .expressions
+                .statement(&StatementKind::Return(None), &mut out),
+            KnotEnding::IMPLICIT => out.push(json!("done")),
+        }
+        out
+    }
+
+    /// The terminator at the end of the container, with the knot's choices in it
+    fn named_content(&self, flags: u32) -> Value {
+        let mut named = Map::new();
+        if let KnotEnding::CHOICES(choices) = &self.knot.ending {
+            for (index, choice) in choices.iter().enumerate() {
+                named.insert(format!("c-{}", index), self.choice(choice));
+            }
+        }
+        named.insert("#f".to_string(), json!(flags));
+        Value::Object(named)
+    }
+
+    /// What happens once the choice is picked: its text is printed, and then its content
+    fn choice(&self, choice: &Choice) -> Value {
+        let mut out = vec![];
+        self.expressions.text(&choice.text, &mut out);
+        out.push(json!("\n"));
+        self.lines(&choice.dialog_lines, &choice.statements, &mut out);
+        out.push(divert_json(&choice.divert));
+        // visits, counted only from the start of the container
+        out.push(json!({ "#f": 5 }));
+        Value::Array(out)
+    }
+
+    fn lines(&self, dialog_lines: &[DialogLine], statements: &[Statement], out: &mut Vec<Value>) {
+        let mut statements = statements.iter().peekable();
+        for (index, line) in dialog_lines.iter().enumerate() {
+            while let Some(statement) = statements.next_if(|s| s.before_line <= index) {
+                self.expressions.statement(&statement.kind, out);
+            }
+            self.dialog_line(line, out);
+        }
+        for statement in statements {
+            self.expressions.statement(&statement.kind, out);
+        }
+    }
+
+    fn dialog_line(&self, line: &DialogLine, out: &mut Vec<Value>) {
+        match (&line.speaker_prefix, &line.speaker) {
+            (Some(prefix), _) => {
+                self.expressions
+                    .text(&format!("{}{}", prefix, line.text), out);
+            }
+            // a speaker added with `DialogLine::with_speaker`
+            (None, Some(speaker)) => {
+                self.expressions
+                    .text(&format!("{}: {}", speaker, line.text), out);
+            }
+            (None, None) => self.expressions.text(&line.text, out),
+        }
+        for tag in &line.tags {
+            push_tag(tag, out);
+        }
+        out.push(json!("\n"));
+    }
+}
+
+impl ExpressionCompiler<'_> {
+    fn is_variable(&self, name: &str) -> bool {
+        self.temps.contains(&name) || self.story.variables.contains_key(name)
+    }
+
+    /// Text, with each `{x}` printing x
+    fn text(&self, text: &str, out: &mut Vec<Value>) {
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            let (before, from_brace) = rest.split_at(open);
+            literal.push_str(before);
+
+            match printed_expression(from_brace) {
+                Some((expression, length)) => {
+                    push_literal(&mut literal, out);
+                    out.push(json!("ev"));
+                    self.expression(&expression, out);
+                    out.push(json!("out"));
+                    out.push(json!("/ev"));
+                    rest = &from_brace[length..];
+                }
+                None => {
+                    literal.push('{');
+                    rest = &from_brace[1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        push_literal(&mut literal, out);
+    }
+
+    fn statement(&self, kind: &StatementKind, out: &mut Vec<Value>) {
+        out.push(json!("ev"));
+        match kind {
+            StatementKind::TempDeclaration(name, value) => {
+                self.expression(value, out);
+                out.push(json!("/ev"));
+                out.push(json!({ "temp=": name }));
+            }
+            StatementKind::Assignment(name, value) => {
+                self.expression(value, out);
+                out.push(json!("/ev"));
+                out.push(self.assignment(name));
+            }
+            StatementKind::AddAssignment(name, value)
+            | StatementKind::SubtractAssignment(name, value) => {
+                out.push(json!({ "VAR?": name }));
+                self.expression(value, out);
+                out.push(match kind {
+                    StatementKind::AddAssignment(..) => json!("+"),
+                    _ => json!("-"),
+                });
+                out.push(json!("/ev"));
+                out.push(self.assignment(name));
+            }
+            StatementKind::Expression(value) => {
+                self.expression(value, out);
+                out.push(json!("pop"));
+                out.push(json!("/ev"));
+            }
+            StatementKind::Return(value) => {
+                match value {
+                    Some(value) => self.expression(value, out),
+                    None => out.push(json!("void")),
+                }
+                out.push(json!("/ev"));
+                out.push(json!("~ret"));
+            }
+        }
+    }
+
+    fn assignment(&self, name: &str) -> Value {
+        if self.temps.contains(&name) {
+            json!({ "temp=": name, "re": true })
+        } else {
+            json!({ "VAR=": name, "re": true })
+        }
+    }
+
+    /// Pushes the expression's value, in postfix order
+    fn expression(&self, expression: &Expression, out: &mut Vec<Value>) {
+        match expression {
+            Expression::Int(n) => out.push(json!(n)),
+            Expression::Float(x) => out.push(json!(x)),
+            Expression::Bool(b) => out.push(json!(b)),
+            Expression::Str(s) => {
+                out.push(json!("str"));
+                out.push(json!(format!("^{}", s)));
+                out.push(json!("/str"));
+            }
+            Expression::DivertTarget(target) => out.push(json!({ "^->": target })),
+            Expression::Variable(name)
+                if !self.is_variable(name) && self.story.knot(name).is_some() =>
+            {
+                out.push(json!({ "CNT?": name }))
+            }
+            Expression::Variable(name) => out.push(json!({ "VAR?": name })),
+            Expression::Unary(operator, operand) => {
+                self.expression(operand, out);
+                out.push(json!(match operator {
+                    UnaryOperator::Negate => "_",
+                    UnaryOperator::Not => "!",
+                }));
+            }
+            Expression::Binary(left, operator, right) => {
+                self.expression(left, out);
+                self.expression(right, out);
+                out.push(json!(operator.to_string()));
+            }
+            Expression::Call(name, arguments) => {
+                for argument in arguments {
+                    self.expression(argument, out);
+                }
+                out.push(match name.as_str() {
+                    "TURNS" => json!("turn"),
+                    "TURNS_SINCE" => json!("turns"),
+                    "READ_COUNT" => json!("readc"),
+                    "CHOICE_COUNT" => json!("choiceCnt"),
+                    "RANDOM" => json!("rnd"),
+                    "SEED_RANDOM" => json!("srnd"),
+                    "INT" | "FLOAT" | "FLOOR" | "CEILING" | "POW" | "MIN" | "MAX" => json!(name),
+                    _ if self.story.externals.contains_key(name) => {
+                        json!({ "x()": name, "exArgs": arguments.len() })
+                    }
+                    _ => json!({ "f()": name }),
+                });
+            }
+        }
+    }
+}
+
+/// The expression that a `{x}` at the start of `text` prints, and how long the `{x}` is
+fn printed_expression(text: &str) -> Option<(Expression, usize)> {
+    let close = text.find('}')?;
+    let inside = &text[1..close];
+    if inside.contains(':') || inside.contains('|') {
+        return None;
+    }
+    parse_inline_expression(inside).map(|expression| (expression, close + 1))
+}
+
+/// The knots that `TURNS_SINCE` asks about, which ink's runtimes only count turns for
+/// when they're flagged to
+fn turns_counted(story: &Story) -> BTreeSet<String> {
+    #[derive(Default)]
+    struct TurnsSince(BTreeSet<String>);
+
+    impl TurnsSince {
+        fn text(&mut self, text: &str) {
+            for (open, _) in text.match_indices('{') {
+                if let Some((expression, _)) = printed_expression(&text[open..]) {
+                    self.visit_expression(&expression);
+                }
+            }
+        }
+    }
+
+    impl<'ast> Visit<'ast> for TurnsSince {
+        fn visit_dialog_line(&mut self, line: &'ast DialogLine) {
+            self.text(&line.text);
+            walk_dialog_line(self, line);
+        }
+
+        fn visit_choice(&mut self, choice: &'ast Choice) {
+            self.text(&choice.text);
+            walk_choice(self, choice);
+        }
+
+        fn visit_expression(&mut self, expression: &Expression) {
+            match expression {
+                Expression::Call(name, arguments) => {
+                    if let (true, [Expression::DivertTarget(target)]) =
+                        (name == "TURNS_SINCE", arguments.as_slice())
+                    {
+                        self.0.insert(target.clone());
+                    }
+                    for argument in arguments {
+                        self.visit_expression(argument);
+                    }
+                }
+                Expression::Unary(_, operand) => self.visit_expression(operand),
+                Expression::Binary(left, _, right) => {
+                    self.visit_expression(left);
+                    self.visit_expression(right);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut turns_since = TurnsSince::default();
+    turns_since.visit_story(story);
+    turns_since.0
+}
+
+fn divert_json(divert: &Divert) -> Value {
+    match divert {
+        Divert::Knot(path) => json!({ "->": path }),
+        Divert::End => json!("end"),
+        Divert::Done => json!("done"),
+        Divert::Variable(name) => json!({ "->": name, "var": true }),
+        Divert::TunnelReturn => json!("->->"),
+    }
+}
+
+fn push_tag(tag: &Tag, out: &mut Vec<Value>) {
+    out.push(json!("#"));
+    out.push(json!(format!("^{}", tag.raw())));
+    out.push(json!("/#"));
+}
+
+fn push_literal(literal: &mut String, out: &mut Vec<Value>) {
+    if !literal.is_empty() {
+        out.push(json!(format!("^{}", literal)));
+        literal.clear();
+    }
+}
@@ -0,0 +1,403 @@
+use crate::comment;
+use combine::parser::char::{alpha_num, char, digit, letter, string};
+use combine::parser::choice::choice;
+use combine::parser::repeat::take_until;
+use combine::{
+    attempt, between, chainl1, many, many1, not_followed_by, optional, satisfy, sep_by, skip_many,
+    ParseError, Parser, Stream,
+};
+use std::fmt;
+
+/// Anything that can be evaluated, as written after `VAR x =`, `~`, or inside `{}`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Expression {
+    Int(i32),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    /// `-> knot`, used as a value
+    DivertTarget(String),
+    /// a global, a temp, or a knot name (which evaluates to its read count)
+    Variable(String),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+    Call(String, Vec<Expression>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BinaryOperator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+impl Expression {
+    fn binary(left: Expression, operator: BinaryOperator, right: Expression) -> Self {
+        Expression::Binary(Box::new(left), operator, Box::new(right))
+    }
+
+    /// Calls `f` with the name of every variable this expression reads, in order.
+    pub fn for_each_variable<'a>(&'a self, f: &mut impl FnMut(&'a str)) {
+        match self {
+            Expression::Variable(name) => f(name),
+            Expression::Unary(_, operand) => operand.for_each_variable(f),
+            Expression::Binary(left, _, right) => {
+                left.for_each_variable(f);
+                right.for_each_variable(f);
+            }
+            Expression::Call(_, arguments) => {
+                for argument in arguments {
+                    argument.for_each_variable(f);
+                }
+            }
+            Expression::Int(_)
+            | Expression::Float(_)
+            | Expression::Bool(_)
+            | Expression::Str(_)
+            | Expression::DivertTarget(_) => {}
+        }
+    }
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOperator::Negate => "-",
+            UnaryOperator::Not => "not ",
+        })
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOperator::Or => "||",
+            BinaryOperator::And => "&&",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessOrEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterOrEqual => ">=",
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+        })
+    }
+}
+
+/// Writes the expression as ink, which parses back to the same expression.
+/// Operands that are themselves operations get parentheses.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn operand(f: &mut fmt::Formatter<'_>, expression: &Expression) -> fmt::Result {
+            match expression {
+                Expression::Unary(..) | Expression::Binary(..) => write!(f, "({})", expression),
+                _ => write!(f, "{}", expression),
+            }
+        }
+
+        match self {
+            Expression::Int(n) => write!(f, "{}", n),
+            Expression::Float(x) => write!(f, "{:?}", x),
+            Expression::Bool(b) => write!(f, "{}", b),
+            Expression::Str(s) => write!(f, "\"{}\"", s),
+            Expression::DivertTarget(target) => write!(f, "-> {}", target),
+            Expression::Variable(name) => f.write_str(name),
+            Expression::Unary(operator, operand_expression) => {
+                write!(f, "{}", operator)?;
+                operand(f, operand_expression)
+            }
+            Expression::Binary(left, operator, right) => {
+                operand(f, left)?;
+                write!(f, " {} ", operator)?;
+                operand(f, right)
+            }
+            Expression::Call(name, arguments) => {
+                write!(f, "{}(", name)?;
+                for (index, argument) in arguments.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", argument)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// spaces, tabs and comments, but never a newline (outside of a comment):
+/// expressions live on a single line
+pub(crate) fn inline_spaces<Input>() -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    skip_many(satisfy(|c| c == ' ' || c == '\t').map(|_| ()).or(comment()))
+}
+
+/// a name, possibly with dots in it for paths like `paris.downtown`
+pub(crate) fn identifier<Input>() -> impl Parser<Input, Output = String>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    letter()
+        .or(char('_'))
+        .and(many::<String, _, _>(
+            alpha_num().or(char('_')).or(char('.')),
+        ))
+        .map(|(first, rest)| format!("{}{}", first, rest))
+}
+
+/// A word like `and` or `not`, which must not be the start of a longer name.
+pub(crate) fn keyword<Input>(word: &'static str) -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    attempt(string(word).skip(not_followed_by(alpha_num().or(char('_')))))
+        .skip(inline_spaces())
+        .map(|_| ())
+}
+
+fn symbol<Input>(s: &'static str) -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    attempt(string(s)).skip(inline_spaces()).map(|_| ())
+}
+
+fn number<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    many1::<String, _, _>(digit())
+        .and(optional(attempt(
+            char('.').with(many1::<String, _, _>(digit())),
+        )))
+        .map(|(whole, fraction)| match fraction {
+            Some(fraction) => Expression::Float(
+                format!("{}.{}", whole, fraction)
+                    .parse()
+                    .unwrap_or_default(),
+            ),
+            None => Expression::Int(whole.parse().unwrap_or(i32::MAX)),
+        })
+        .skip(inline_spaces())
+}
+
+fn primary<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    choice((
+        between(symbol("("), symbol(")"), parse_expression()),
+        number(),
+        between(char('"'), char('"'), take_until::<String, _, _>(char('"')))
+            .skip(inline_spaces())
+            .map(Expression::Str),
+        symbol("->")
+            .with(identifier())
+            .skip(inline_spaces())
+            .map(Expression::DivertTarget),
+        keyword("true").map(|_| Expression::Bool(true)),
+        keyword("false").map(|_| Expression::Bool(false)),
+        identifier()
+            .skip(inline_spaces())
+            .and(optional(between(
+                symbol("("),
+                symbol(")"),
+                sep_by::<Vec<Expression>, _, _, _>(parse_expression(), symbol(",")),
+            )))
+            .map(|(name, arguments)| match arguments {
+                Some(arguments) => Expression::Call(name, arguments),
+                None => Expression::Variable(name),
+            }),
+    ))
+}
+
+fn unary<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    many::<Vec<UnaryOperator>, _, _>(choice((
+        attempt(char('-').skip(not_followed_by(char('>'))))
+            .skip(inline_spaces())
+            .map(|_| UnaryOperator::Negate),
+        attempt(char('!').skip(not_followed_by(char('='))))
+            .skip(inline_spaces())
+            .map(|_| UnaryOperator::Not),
+        keyword("not").map(|_| UnaryOperator::Not),
+    )))
+    .and(primary())
+    .map(|(operators, operand)| {
+        operators
+            .into_iter()
+            .rev()
+            .fold(operand, |operand, operator| {
+                Expression::Unary(operator, Box::new(operand))
+            })
+    })
+}
+
+/// Turns a parser for an operator into one that `chainl1` can use to join both sides.
+fn join<Input, P>(
+    operator: P,
+) -> impl Parser<Input, Output = impl FnOnce(Expression, Expression) -> Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    P: Parser<Input, Output = BinaryOperator>,
+{
+    operator.map(|operator| move |left, right| Expression::binary(left, operator, right))
+}
+
+fn multiplicative<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    chainl1(
+        unary(),
+        join(choice((
+            symbol("*").map(|_| BinaryOperator::Multiply),
+            symbol("/").map(|_| BinaryOperator::Divide),
+            symbol("%").map(|_| BinaryOperator::Modulo),
+            keyword("mod").map(|_| BinaryOperator::Modulo),
+        ))),
+    )
+}
+
+fn additive<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    chainl1(
+        multiplicative(),
+        join(choice((
+            symbol("+").map(|_| BinaryOperator::Add),
+            attempt(char('-').skip(not_followed_by(char('>'))))
+                .skip(inline_spaces())
+                .map(|_| BinaryOperator::Subtract),
+        ))),
+    )
+}
+
+fn comparison<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    chainl1(
+        additive(),
+        join(choice((
+            symbol("<=").map(|_| BinaryOperator::LessOrEqual),
+            symbol(">=").map(|_| BinaryOperator::GreaterOrEqual),
+            symbol("<").map(|_| BinaryOperator::Less),
+            symbol(">").map(|_| BinaryOperator::Greater),
+        ))),
+    )
+}
+
+fn equality<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    chainl1(
+        comparison(),
+        join(choice((
+            symbol("==").map(|_| BinaryOperator::Equal),
+            symbol("!=").map(|_| BinaryOperator::NotEqual),
+        ))),
+    )
+}
+
+fn and<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    chainl1(
+        equality(),
+        join(symbol("&&").or(keyword("and")).map(|_| BinaryOperator::And)),
+    )
+}
+
+fn or<Input>() -> impl Parser<Input, Output = Expression>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    chainl1(
+        and(),
+        join(symbol("||").or(keyword("or")).map(|_| BinaryOperator::Or)),
+    )
+}
+
+combine::parser! {
+    /// Parses a whole expression, along with any spaces after it (but not a newline).
+    pub(crate) fn parse_expression[Input]()(Input) -> Expression
+    where [
+        Input: Stream<Token = char>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ]
+    {
+        or()
+    }
+}
+
+/// The expressions inside `{}` in a piece of text: `{x}` prints x, and in `{x: a|b}` only the
+/// condition is an expression. Anything that doesn't parse as an expression is left alone.
+pub(crate) fn inline_expressions(text: &str) -> Vec<Expression> {
+    text.split('{')
+        .skip(1)
+        .filter_map(|after_brace| after_brace.split('}').next())
+        .filter_map(|inside| match inside.find(':') {
+            Some(colon) => Some(&inside[..colon]),
+            None if inside.contains('|') => None,
+            None => Some(inside),
+        })
+        .filter_map(parse_inline_expression)
+        .collect()
+}
+
+/// `source` as a whole expression, with any spaces around it
+pub(crate) fn parse_inline_expression(source: &str) -> Option<Expression> {
+    use combine::{eof, EasyParser};
+
+    inline_spaces()
+        .with(parse_expression())
+        .skip(eof())
+        .easy_parse(source)
+        .ok()
+        .map(|(expression, _)| expression)
+}
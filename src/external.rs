@@ -0,0 +1,89 @@
+use crate::Value;
+use std::convert::TryFrom;
+
+/// Takes the arguments as ink values; the error says what was wrong with them
+pub(crate) type BoxedExternal<'a> = Box<dyn FnMut(Vec<Value>) -> Result<Value, String> + 'a>;
+
+/// A Rust closure that can stand in for an ink function, with `StoryRunner::bind_external`.
+///
+/// This is implemented for closures of up to four arguments, where each argument
+/// is an `i32`, `f64`, `bool`, `String` or `Value`, and whatever they give back
+/// turns into a `Value` (`()` prints as nothing).
+pub trait ExternalFunction<'a, Args> {
+    fn into_boxed(self) -> BoxedExternal<'a>;
+}
+
+macro_rules! external_function {
+    ($($argument:ident),*) => {
+        impl<'a, F, R, $($argument),*> ExternalFunction<'a, ($($argument,)*)> for F
+        where
+            F: FnMut($($argument),*) -> R + 'a,
+            R: Into<Value>,
+            $($argument: TryFrom<Value>,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_boxed(mut self) -> BoxedExternal<'a> {
+                Box::new(move |arguments: Vec<Value>| {
+                    let expected = <[&str]>::len(&[$(stringify!($argument)),*]);
+                    if arguments.len() != expected {
+                        return Err(format!(
+                            "{} arguments, not {}",
+                            arguments.len(),
+                            expected
+                        ));
+                    }
+                    let mut arguments = arguments.into_iter();
+                    $(
+                        let value = arguments.next().unwrap();
+                        let $argument = <$argument>::try_from(value.clone())
+                            .map_err(|_| format!("can't use {:?} here", value))?;
+                    )*
+                    Ok(self($($argument),*).into())
+                })
+            }
+        }
+    };
+}
+
+external_function!();
+external_function!(A);
+external_function!(A, B);
+external_function!(A, B, C);
+external_function!(A, B, C, D);
+
+impl TryFrom<Value> for i32 {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        value.as_int().ok_or(value)
+    }
+}
+
+/// Ints are widened to floats
+impl TryFrom<Value> for f64 {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        value.as_float().ok_or(value)
+    }
+}
+
+/// Anything can be a bool, by whether it's truthy
+impl TryFrom<Value> for bool {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        Ok(value.is_truthy())
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            _ => Err(value),
+        }
+    }
+}
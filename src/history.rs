@@ -0,0 +1,113 @@
+use crate::runtime::State;
+use crate::{Line, RuntimeError, StoryRunner};
+use std::collections::VecDeque;
+
+/// The states a runner was in at its last few choices, for going back to them
+#[derive(Default)]
+pub(crate) struct History {
+    /// how many choice points to keep; none are kept if it's 0
+    limit: usize,
+    /// oldest first
+    snapshots: VecDeque<Snapshot>,
+    /// every line given out since the choice before the oldest snapshot
+    log: Vec<Line>,
+}
+
+struct Snapshot {
+    state: State,
+    /// how long the log was then
+    log_length: usize,
+}
+
+impl History {
+    pub(crate) fn record_line(&mut self, line: &Line) {
+        if self.limit > 0 {
+            self.log.push(line.clone());
+        }
+    }
+
+    /// Keeps the state from just before a choice is made
+    pub(crate) fn record_choice_point(&mut self, state: &State) {
+        if self.limit == 0 {
+            return;
+        }
+        self.snapshots.push_back(Snapshot {
+            state: state.clone(),
+            log_length: self.log.len(),
+        });
+        self.forget_past(self.limit);
+    }
+
+    /// Keeps `limit` snapshots, and the lines since the choice before the oldest of them
+    fn forget_past(&mut self, limit: usize) {
+        while self.snapshots.len() > limit {
+            let forgotten = self.snapshots.pop_front().unwrap().log_length;
+            self.log.drain(..forgotten);
+            for snapshot in &mut self.snapshots {
+                snapshot.log_length -= forgotten;
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.snapshots.clear();
+        self.log.clear();
+    }
+}
+
+impl StoryRunner<'_> {
+    /// Starts keeping the state at each of the last `limit` choices, so that
+    /// `rewind` can go back to them. Setting it to 0 stops keeping history.
+    pub fn keep_history(&mut self, limit: usize) {
+        self.history.limit = limit;
+        self.history.forget_past(limit);
+        if limit == 0 {
+            self.history.clear();
+        }
+    }
+
+    /// How many choices `rewind` can go back
+    pub fn history_len(&self) -> usize {
+        self.history.snapshots.len()
+    }
+
+    /// Every line given out since the choice before the oldest one that `rewind`
+    /// can go back to, so that going back there still shows what led up to it
+    pub fn text_log(&self) -> &[Line] {
+        &self.history.log
+    }
+
+    /// Goes back to before the last `choices` choices were made, with the same choices
+    /// on offer, and the variables, visit counts and text log as they were then.
+    /// Observers hear about any globals that go back to an older value.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner};
+    ///
+    /// let story = parse_story("Where to?\n+ Left\n    -> INTRO\n+ Right\n    -> END\n");
+    /// let mut runner = StoryRunner::new(&story);
+    /// runner.keep_history(10);
+    /// runner.continue_line().unwrap();
+    /// runner.choose(1).unwrap();
+    /// assert_eq!(runner.continue_line().unwrap().text(), "Right");
+    ///
+    /// runner.rewind(1).unwrap();
+    /// assert_eq!(runner.current_choices(), vec!["Left", "Right"]);
+    /// assert_eq!(runner.text_log().len(), 1);
+    /// ```
+    pub fn rewind(&mut self, choices: usize) -> Result<(), RuntimeError> {
+        if choices == 0 {
+            return Ok(());
+        }
+        let available = self.history.snapshots.len();
+        if choices > available {
+            return Err(RuntimeError::CannotRewind(choices));
+        }
+
+        self.history.snapshots.truncate(available - choices + 1);
+        let snapshot = self.history.snapshots.pop_back().unwrap();
+        self.history.log.truncate(snapshot.log_length);
+        self.replace_state(snapshot.state);
+        Ok(())
+    }
+}
@@ -1,83 +1,550 @@
 #![warn(rust_2018_idioms)]
 
+mod check;
+mod compile;
+mod expression;
+mod external;
+mod history;
+mod load;
+mod random;
+mod runtime;
+mod save_state;
+#[cfg(feature = "serde")]
+mod serialization;
+mod span;
+mod speakers;
+mod tag_handlers;
+mod tags;
 mod tests;
+mod value;
+pub mod visit;
 
-use combine::parser::char::{char, spaces, string};
+pub use check::{check_story, story_warnings, StoryError, StoryWarning};
+pub use compile::{compile_story, INK_VERSION};
+pub use expression::{BinaryOperator, Expression, UnaryOperator};
+pub use external::ExternalFunction;
+pub use load::{load_ink_json, LoadError, Unrepresentable, OLDEST_INK_VERSION};
+pub use runtime::{Evaluated, Line, RuntimeError, StoryRunner, DEFAULT_FLOW};
+pub use save_state::{RestoreError, STATE_VERSION};
+#[cfg(feature = "serde")]
+pub use serialization::{UnsupportedVersion, FORMAT_VERSION};
+pub use span::{
+    line_column, story_stream, BytePositioner, FileId, Location, Span, Spanned, StoryStream,
+};
+pub use speakers::SpeakerSyntax;
+pub use tag_handlers::TagSource;
+pub use tags::{Tag, TagSyntax};
+pub use value::Value;
+pub use visit::{Visit, VisitMut};
+
+use combine::parser::char::{char, space, string};
 use combine::parser::choice::choice;
 use combine::parser::repeat::take_until;
 use combine::{
-    attempt, eof, many, many1, optional, value, ParseError, Parser, RangeStream, Stream,
+    attempt, many, many1, optional, sep_by, skip_many, value, ParseError, Parser, RangeStream,
+    Stream,
 };
-use combine::{not_followed_by, satisfy, EasyParser};
+use combine::{not_followed_by, position, satisfy, EasyParser};
+use expression::{identifier, inline_spaces, keyword, parse_expression};
+use span::spanned;
 use std::collections::BTreeMap;
-
-// TODO: get rid of comments, using a nice function that I can use everywhere
-//       - maybe rest_of_the_line can absorb comments, and that's good enough?
+use tags::find_tag;
 
 // TODO: get rid of trailing "==="s on knot titles
 // TODO: pass state along, so when parsing fails I can debug it.
 // TODO: variables, conditionals, etc.
 
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DialogLine {
     text: String,
-    tags: Vec<String>,
+    tags: Vec<Tag>,
+    /// filled in by `Story::extract_speakers`
+    speaker: Option<String>,
+    /// what `Story::extract_speakers` took off the front of the text, like `Fogg - `,
+    /// so that the line can be written out again the way it was
+    #[cfg_attr(feature = "serde", serde(default))]
+    speaker_prefix: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Span,
 }
 
 type KnotTitle = String;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Divert {
-    knot_title: KnotTitle,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Divert {
+    /// `-> paris`, or `-> paris.downtown`
+    Knot(KnotTitle),
+    /// `-> END`, which ends the whole story
+    End,
+    /// `-> DONE`, which ends the current flow
+    Done,
+    /// `-> x`, where `x` is a variable holding a divert target
+    Variable(String),
+    /// `->->`
+    TunnelReturn,
 }
-#[derive(Debug, PartialEq, Eq, Clone)]
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StatementKind {
+    /// `~ temp x = 5`, only visible in the knot it's declared in
+    TempDeclaration(String, Expression),
+    /// `~ x = 5`
+    Assignment(String, Expression),
+    /// `~ x += 5`
+    AddAssignment(String, Expression),
+    /// `~ x -= 5`
+    SubtractAssignment(String, Expression),
+    /// `~ do_something()`
+    Expression(Expression),
+    /// `~ return x`, which ends a function with x (or with nothing)
+    Return(Option<Expression>),
+}
+
+/// A logic line, starting with `~`
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Statement {
+    /// index of the dialog line this runs before;
+    /// equal to the number of dialog lines if it comes after all of them
+    before_line: usize,
+    kind: StatementKind,
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Choice {
     text: String,
     dialog_lines: Vec<DialogLine>,
-    divert: Divert,
+    statements: Vec<Statement>,
+    divert: Spanned<Divert>,
+    /// from the `+` to the end of the divert
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Span,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum KnotEnding {
     CHOICES(Vec<Choice>), // TODO: should this be a BTreeMap?
-    DIVERT(Divert),
+    DIVERT(Spanned<Divert>),
+    /// the knot's content just runs out, which ends the flow (see `story_warnings`)
+    IMPLICIT,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Knot {
     title: String,
+    /// `=== paris(day, -> next) ===`, or a function's arguments
+    #[cfg_attr(feature = "serde", serde(default))]
+    parameters: Vec<String>,
+    /// `=== function name() ===`, which is called rather than diverted to
+    #[cfg_attr(feature = "serde", serde(default))]
+    is_function: bool,
+    /// tags that come before the knot's first dialog line
+    tags: Vec<Tag>,
     dialog_lines: Vec<DialogLine>,
+    statements: Vec<Statement>,
     ending: KnotEnding,
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Span,
 }
 
-#[derive(Default, Debug, PartialEq, Eq, Clone)]
+#[derive(Default, Debug, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        into = "serialization::StoryJson",
+        try_from = "serialization::StoryJson"
+    )
+)]
 pub struct Story {
-    knots: BTreeMap<KnotTitle, Knot>,
+    /// tags at the very top of the story, before any content
+    global_tags: Vec<Tag>,
+    /// `VAR` declarations, with their initial values
+    variables: BTreeMap<String, Expression>,
+    /// `EXTERNAL` declarations of functions the game provides, with their parameters
+    externals: BTreeMap<String, Vec<String>>,
+    /// in the order they were written, even if two have the same title
+    knots: Vec<Knot>,
 }
 
 impl From<&str> for Divert {
+    /// Can't tell a variable from a knot, so that's left to `Story::resolve_divert_variables`.
     fn from(s: &str) -> Self {
-        Divert {
-            knot_title: s.to_string(),
+        match s {
+            "END" => Divert::End,
+            "DONE" => Divert::Done,
+            _ => Divert::Knot(s.to_string()),
         }
     }
 }
 
+impl From<&str> for Spanned<Divert> {
+    fn from(s: &str) -> Self {
+        Divert::from(s).into()
+    }
+}
+
 impl Default for Knot {
     fn default() -> Self {
         Knot {
             title: "".to_string(),
+            parameters: vec![],
+            is_function: false,
+            tags: vec![],
             dialog_lines: vec![],
-            ending: KnotEnding::CHOICES(vec![]),
+            statements: vec![],
+            ending: KnotEnding::IMPLICIT,
+            span: Span::default(),
+        }
+    }
+}
+
+impl DialogLine {
+    pub fn new(text: &str) -> Self {
+        text.into()
+    }
+
+    pub fn with_tag(mut self, tag: Tag) -> Self {
+        self.tags.push(tag);
+        self
+    }
+
+    pub fn with_speaker(mut self, speaker: &str) -> Self {
+        self.speaker = Some(speaker.to_string());
+        self
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    pub fn speaker(&self) -> Option<&str> {
+        self.speaker.as_deref()
+    }
+
+    /// `line.tag("speaker")` finds `#speaker: Fogg`
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        find_tag(&self.tags, name)
+    }
+
+    /// the text and tags, without the leading whitespace or the newline
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Divert {
+    /// the knot this goes to, if it goes to one by name
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            Divert::Knot(path) => Some(path),
+            _ => None,
         }
     }
 }
 
+impl Statement {
+    /// Runs before the dialog line at `before_line`
+    pub fn new(before_line: usize, kind: StatementKind) -> Self {
+        Statement {
+            before_line,
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    pub fn before_line(&self) -> usize {
+        self.before_line
+    }
+
+    pub fn kind(&self) -> &StatementKind {
+        &self.kind
+    }
+
+    /// from the `~` to the end of the line
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Choice {
+    pub fn new(text: &str, divert: Divert) -> Self {
+        Choice {
+            text: text.to_string(),
+            dialog_lines: vec![],
+            statements: vec![],
+            divert: divert.into(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn with_line(mut self, line: DialogLine) -> Self {
+        self.dialog_lines.push(line);
+        self
+    }
+
+    /// The statement runs after the lines added so far
+    pub fn with_statement(mut self, kind: StatementKind) -> Self {
+        self.statements
+            .push(Statement::new(self.dialog_lines.len(), kind));
+        self
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn dialog_lines(&self) -> &[DialogLine] {
+        &self.dialog_lines
+    }
+
+    pub fn statements(&self) -> &[Statement] {
+        &self.statements
+    }
+
+    /// derefs to the `Divert`, and has its span
+    pub fn divert(&self) -> &Spanned<Divert> {
+        &self.divert
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Knot {
+    /// An empty knot, which ends without a divert until it gets one
+    pub fn new(title: &str) -> Self {
+        Knot {
+            title: title.to_string(),
+            ..Knot::default()
+        }
+    }
+
+    /// An empty `=== function title(parameters) ===`
+    pub fn function(title: &str, parameters: &[&str]) -> Self {
+        Knot {
+            is_function: true,
+            ..Knot::new(title).with_parameters(parameters)
+        }
+    }
+
+    pub fn with_parameters(mut self, parameters: &[&str]) -> Self {
+        self.parameters = parameters.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn with_tag(mut self, tag: Tag) -> Self {
+        self.tags.push(tag);
+        self
+    }
+
+    pub fn with_line(mut self, line: DialogLine) -> Self {
+        self.dialog_lines.push(line);
+        self
+    }
+
+    /// The statement runs after the lines added so far
+    pub fn with_statement(mut self, kind: StatementKind) -> Self {
+        self.statements
+            .push(Statement::new(self.dialog_lines.len(), kind));
+        self
+    }
+
+    /// Adds to the knot's choices, replacing a divert if it had one
+    pub fn with_choice(mut self, choice: Choice) -> Self {
+        match &mut self.ending {
+            KnotEnding::CHOICES(choices) => choices.push(choice),
+            _ => self.ending = KnotEnding::CHOICES(vec![choice]),
+        }
+        self
+    }
+
+    /// Replaces the knot's ending
+    pub fn with_divert(mut self, divert: Divert) -> Self {
+        self.ending = KnotEnding::DIVERT(divert.into());
+        self
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn parameters(&self) -> &[String] {
+        &self.parameters
+    }
+
+    pub fn is_function(&self) -> bool {
+        self.is_function
+    }
+
+    /// tags that come before the knot's first dialog line
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    pub fn dialog_lines(&self) -> &[DialogLine] {
+        &self.dialog_lines
+    }
+
+    pub fn statements(&self) -> &[Statement] {
+        &self.statements
+    }
+
+    pub fn ending(&self) -> &KnotEnding {
+        &self.ending
+    }
+
+    /// empty unless the knot ends in choices
+    pub fn choices(&self) -> &[Choice] {
+        match &self.ending {
+            KnotEnding::CHOICES(choices) => choices,
+            _ => &[],
+        }
+    }
+
+    /// `None` unless the knot ends in a divert
+    pub fn divert(&self) -> Option<&Spanned<Divert>> {
+        match &self.ending {
+            KnotEnding::DIVERT(divert) => Some(divert),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        find_tag(&self.tags, name)
+    }
+
+    /// from the title (if it has one) to the end of its ending
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Story {
+    pub fn new() -> Self {
+        Story::default()
+    }
+
+    pub fn with_global_tag(mut self, tag: Tag) -> Self {
+        self.global_tags.push(tag);
+        self
+    }
+
+    pub fn with_variable(mut self, name: &str, value: Expression) -> Self {
+        self.variables.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn with_external(mut self, name: &str, parameters: &[&str]) -> Self {
+        let parameters = parameters.iter().map(|p| p.to_string()).collect();
+        self.externals.insert(name.to_string(), parameters);
+        self
+    }
+
+    /// Knots are kept in the order they're added; the first one is where the story starts
+    pub fn with_knot(mut self, knot: Knot) -> Self {
+        self.knots.push(knot);
+        self
+    }
+
+    /// tags at the very top of the story, before any content
+    pub fn global_tags(&self) -> &[Tag] {
+        &self.global_tags
+    }
+
+    /// `VAR` declarations, with their initial values
+    pub fn variables(&self) -> &BTreeMap<String, Expression> {
+        &self.variables
+    }
+
+    /// `EXTERNAL` declarations of functions the game provides, with their parameters
+    pub fn externals(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.externals
+    }
+
+    /// in the order they were written, starting with `INTRO`
+    pub fn knots(&self) -> &[Knot] {
+        &self.knots
+    }
+
+    /// The first knot with this title
+    pub fn knot(&self, title: &str) -> Option<&Knot> {
+        self.knots.iter().find(|knot| knot.title == title)
+    }
+
+    /// The first function with this name
+    pub fn function(&self, name: &str) -> Option<&Knot> {
+        self.knots
+            .iter()
+            .find(|knot| knot.is_function && knot.title == name)
+    }
+
+    /// `-> x` parses as a divert to a knot, unless `x` turns out to be a global or a temp.
+    fn resolve_divert_variables(&mut self) {
+        let variables = &self.variables;
+        for knot in self.knots.iter_mut() {
+            let temps: Vec<String> = check::temps(knot).map(str::to_string).collect();
+            let is_variable =
+                |name: &str| variables.contains_key(name) || temps.iter().any(|t| t == name);
+
+            let diverts: Vec<&mut Spanned<Divert>> = match &mut knot.ending {
+                KnotEnding::CHOICES(choices) => choices
+                    .iter_mut()
+                    .map(|choice| &mut choice.divert)
+                    .collect(),
+                KnotEnding::DIVERT(divert) => vec![divert],
+                KnotEnding::IMPLICIT => vec![],
+            };
+            for divert in diverts {
+                if let Divert::Knot(name) = divert.node() {
+                    if is_variable(name) {
+                        *divert = Spanned::new(Divert::Variable(name.clone()), divert.span());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn global_tag(&self, name: &str) -> Option<&Tag> {
+        find_tag(&self.global_tags, name)
+    }
+
+    /// Splits every tag in the story again, for hosts whose tags don't look like
+    /// `#key: value` or `#key value`.
+    pub fn split_tags(&mut self, syntax: &TagSyntax) {
+        struct SplitTags<'a>(&'a TagSyntax);
+
+        impl VisitMut for SplitTags<'_> {
+            fn visit_tag_mut(&mut self, tag: &mut Tag) {
+                tag.resplit(self.0);
+            }
+        }
+
+        SplitTags(syntax).visit_story_mut(self);
+    }
+}
+
 impl From<&str> for DialogLine {
     fn from(s: &str) -> Self {
         Self {
             text: s.to_string(),
             tags: vec![],
+            speaker: None,
+            speaker_prefix: None,
+            span: Span::default(),
         }
     }
 }
@@ -90,24 +557,13 @@ where
     char('\n').or(char('\r').skip(char('\n'))).map(|_| ())
 }
 
-/// grabs the rest of the line, and consumes any trailing newline marker
-fn rest_of_the_line<Input>() -> impl Parser<Input, Output = String>
-where
-    Input: Stream<Token = char>,
-    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
-{
-    // TODO: can this be simplified? I just want to grab everything until we hit \n or \r
-    //       - maybe like we do in tag()?
-    many1::<String, _, _>(satisfy(|c| c != '\n' && c != '\r')).skip(optional(newline_character()))
-}
-
 fn single_line_comment<Input>() -> impl Parser<Input, Output = ()>
 where
     Input: Stream<Token = char>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     attempt(string("//"))
-        .with(many1::<String, _, _>(satisfy(|c| c != '\n' && c != '\r')))
+        .with(many::<String, _, _>(satisfy(|c| c != '\n' && c != '\r')))
         .with(value(()))
 }
 
@@ -122,201 +578,478 @@ where
         .with(value(()))
 }
 
-fn tag<Input>() -> impl Parser<Input, Output = String>
+fn comment<Input>() -> impl Parser<Input, Output = ()>
 where
     Input: Stream<Token = char>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    attempt(string("#"))
-        .with(take_until::<String, _, _>(choice((
-            string("#"),
-            string("\n"),
-            string("\r\n"),
-            eof().map(|_| ""),
-        ))))
-        .map(|x| x.trim().to_string())
+    single_line_comment().or(multi_line_comment())
 }
 
-// TODO: do this like I'm doing in dialog_lines(), then have dialog_lines() use this function
-fn rest_of_the_line_ignoring_comments_with_tags<Input>() -> impl Parser<Input, Output = DialogLine>
+/// Like spaces(), but comments count as whitespace too.
+fn whitespace<Input>() -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    skip_many(space().map(|_| ()).or(comment()))
+}
+
+/// A piece of text on a single line, which stops at `#` and at `//`.
+/// A `/* */` comment disappears, even when it spans several lines, and a lone `/` is just text.
+fn text_chunk<Input>() -> impl Parser<Input, Output = String>
 where
     Input: Stream<Token = char>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    // TODO: this needs to ignore inline comments. :/
-    //       maybe we call this a bunch of times, then flatten the resulting Strings?
-    //       ... though having a newline within the multi_line_comment seems to break things ... which is unexpected ...
-    many1::<String, _, _>(satisfy(|c| c != '\n' && c != '\r' && c != '/' && c != '#'))
-        // TODO: this needs to be "//", not just a single slash, or that's going to cause prooooooblems...
-        // TODO: can I use take_until, like we're doing in tag()?
-        .and(optional(many1::<Vec<String>, _, _>(tag())))
+    choice((
+        many1::<String, _, _>(satisfy(|c| c != '\n' && c != '\r' && c != '/' && c != '#')),
+        multi_line_comment().map(|_| String::new()),
+        attempt(char('/').skip(not_followed_by(char('/')))).map(|c: char| c.to_string()),
+    ))
+}
+
+/// grabs the rest of the line, without any comments, and consumes any trailing newline marker
+fn rest_of_the_line<Input>() -> impl Parser<Input, Output = String>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    many1::<Vec<String>, _, _>(text_chunk().or(char('#').map(|c: char| c.to_string())))
+        .skip(optional(single_line_comment()))
+        .skip(optional(newline_character()))
+        .map(|chunks| chunks.concat().trim().to_string())
+}
+
+fn tag<Input>() -> impl Parser<Input, Output = Tag>
+where
+    Input: Stream<Token = char, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    spanned(char('#').with(many::<Vec<String>, _, _>(text_chunk())))
+        .map(|(chunks, span)| Tag::from(chunks.concat().as_str()).with_span(span))
+}
+
+/// A line with nothing but tags on it
+fn tag_line<Input>() -> impl Parser<Input, Output = Vec<Tag>>
+where
+    Input: Stream<Token = char, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    many1::<Vec<Tag>, _, _>(tag())
+        .skip(optional(single_line_comment()))
+        .skip(optional(newline_character()))
+}
+
+/// Text and then tags, up to the end of the line, with comments taken out
+fn rest_of_the_line_ignoring_comments_with_tags<Input>() -> impl Parser<Input, Output = DialogLine>
+where
+    Input: Stream<Token = char, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    spanned(many1::<Vec<String>, _, _>(text_chunk()).and(many::<Vec<Tag>, _, _>(tag())))
         .skip(optional(single_line_comment()))
-        .skip(optional(multi_line_comment()))
-        .skip(optional(choice((string("\n"), string("\r\n")))))
-        .map(|(s, tags)| DialogLine {
-            text: s.trim_end().into(),
-            tags: tags.unwrap_or_default(),
+        .skip(optional(newline_character()))
+        .map(|((chunks, tags), span)| DialogLine {
+            text: chunks.concat().trim().into(),
+            tags,
+            speaker: None,
+            speaker_prefix: None,
+            span,
         })
 }
 
-/// This does not absorb the following newline character ...right?
+/// This absorbs the following newline character, and any blank lines and comments after it
+///
+/// ```
+/// use combine::EasyParser;
+/// use ink_parser::{dialog_line, story_stream, FileId};
+///
+/// let (line, _) = dialog_line()
+///     .easy_parse(story_stream("Bonjour! #french\n", FileId::default()))
+///     .unwrap();
+/// assert_eq!(line.text(), "Bonjour!");
+/// ```
 pub fn dialog_line<Input>() -> impl Parser<Input, Output = DialogLine>
+where
+    Input: Stream<Token = char, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    not_followed_by(choice((
+        string("->"),
+        string("+"),
+        string("~"),
+        string("=="),
+    )))
+    .skip(whitespace())
+    .skip(not_followed_by(choice((
+        attempt(string("->")),
+        attempt(string("+")),
+        attempt(string("~")),
+        attempt(string("==")),
+    )))) // TODO: when this fails, we don't gracefully do anything...
+    .with(rest_of_the_line_ignoring_comments_with_tags())
+    // TODO: I would love to put divert() right in here; not sure why I can't
+    .skip(whitespace())
+}
+
+/// `VAR name = value`
+fn global_variable<Input>() -> impl Parser<Input, Output = (String, Expression)>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    attempt(whitespace().with(keyword("VAR")))
+        .with(identifier())
+        .skip(inline_spaces())
+        .skip(char('='))
+        .skip(inline_spaces())
+        .and(parse_expression())
+        .skip(optional(newline_character()))
+}
+
+/// `EXTERNAL name(a, b)`
+fn external_declaration<Input>() -> impl Parser<Input, Output = (String, Vec<String>)>
 where
     Input: Stream<Token = char>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    not_followed_by(choice((string("->"), string("+"))))
-        .skip(spaces())
-        .skip(not_followed_by(choice((
-            attempt(string("->")),
-            attempt(string("+")),
-        )))) // TODO: when this fails, we don't gracefully do anything...
-        .with(many1::<String, _, _>(
-            // TODO: make this a function: grab a line, ignoring multiline comments
-            many1::<String, _, _>(
-                satisfy(|c| c != '\n' && c != '\r' && c != '/' && c != '#'), // TODO: do better than this, for comments
-            ) // TODO: do we really need to find a character before a multi line comment? Hmm...
-            .skip(optional(many1::<(), _, _>(multi_line_comment()))),
+    attempt(whitespace().with(keyword("EXTERNAL")))
+        .with(identifier())
+        .skip(inline_spaces())
+        .and(parameters())
+        .skip(inline_spaces())
+        .skip(optional(newline_character()))
+}
+
+/// `(a, b)`, after a knot's title or a function's name
+fn parameters<Input>() -> impl Parser<Input, Output = Vec<String>>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    let parameter = optional(attempt(string("->").skip(inline_spaces())))
+        .with(identifier())
+        .skip(inline_spaces());
+    char('(')
+        .skip(inline_spaces())
+        .with(sep_by(parameter, char(',').skip(inline_spaces())))
+        .skip(char(')'))
+}
+
+/// A `~` line, along with its newline
+fn statement<Input>() -> impl Parser<Input, Output = (StatementKind, Span)>
+where
+    Input: Stream<Token = char, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    let assignment_operator = choice((
+        attempt(string("+=")),
+        attempt(string("-=")),
+        attempt(string("=").skip(not_followed_by(char('=')))),
+    ));
+
+    spanned(
+        char('~').skip(inline_spaces()).with(choice((
+            attempt(keyword("return"))
+                .with(optional(parse_expression()))
+                .map(StatementKind::Return),
+            keyword("temp")
+                .with(identifier())
+                .skip(inline_spaces())
+                .skip(char('='))
+                .skip(inline_spaces())
+                .and(parse_expression())
+                .map(|(name, value)| StatementKind::TempDeclaration(name, value)),
+            attempt(
+                identifier()
+                    .skip(inline_spaces())
+                    .and(assignment_operator)
+                    .skip(inline_spaces()),
+            )
+            .and(parse_expression())
+            .map(|((name, operator), value)| match operator {
+                "+=" => StatementKind::AddAssignment(name, value),
+                "-=" => StatementKind::SubtractAssignment(name, value),
+                _ => StatementKind::Assignment(name, value),
+            }),
+            parse_expression().map(StatementKind::Expression),
+        ))),
+    )
+    .skip(optional(newline_character()))
+}
+
+enum BodyLine {
+    Dialog(DialogLine),
+    Logic(StatementKind, Span),
+    Tags(Vec<Tag>),
+}
+
+#[derive(Debug, PartialEq, Default)]
+struct Body {
+    /// tags before the first dialog line
+    tags: Vec<Tag>,
+    dialog_lines: Vec<DialogLine>,
+    statements: Vec<Statement>,
+}
+
+/// Dialog lines and `~` lines, which are kept apart but remember their order.
+/// Tags on a line of their own belong to the next dialog line (or the previous one,
+/// if there are no more), except for those before the first dialog line.
+///
+/// Must call whitespace() before calling this,
+/// because we can't make it optional() if whitespace() consumes input
+/// TODO: fix that, so we can have whitespace() at the start of this properly. (see below)
+fn body<'a, Input>() -> impl Parser<Input, Output = Body>
+where
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    many1::<Vec<BodyLine>, _, _>(
+        choice((
+            statement().map(|(kind, span)| BodyLine::Logic(kind, span)),
+            tag_line().map(BodyLine::Tags),
+            dialog_line().map(BodyLine::Dialog),
         ))
-        .and(optional(many1::<Vec<String>, _, _>(tag())))
-        .skip(optional(single_line_comment()))
-        // TODO: I would love to put divert() right in here; not sure why I can't
-        .skip(spaces())
-        .map(|(s, tags)| DialogLine {
-            text: s.trim().to_string(),
-            tags: tags.unwrap_or_default(),
-        })
+        .skip(whitespace()),
+    )
+    .map(|body_lines| {
+        let mut body = Body::default();
+        let mut pending_tags = vec![];
+        for body_line in body_lines {
+            match body_line {
+                BodyLine::Dialog(line) if body.dialog_lines.is_empty() => {
+                    body.tags.append(&mut pending_tags);
+                    body.dialog_lines.push(line);
+                }
+                BodyLine::Dialog(mut line) => {
+                    pending_tags.append(&mut line.tags);
+                    line.tags = std::mem::take(&mut pending_tags);
+                    body.dialog_lines.push(line);
+                }
+                BodyLine::Logic(kind, span) => body.statements.push(Statement {
+                    before_line: body.dialog_lines.len(),
+                    kind,
+                    span,
+                }),
+                BodyLine::Tags(mut tags) => pending_tags.append(&mut tags),
+            }
+        }
+        match body.dialog_lines.last_mut() {
+            Some(line) => line.tags.append(&mut pending_tags),
+            None => body.tags.append(&mut pending_tags),
+        }
+        body
+    })
 }
 
-/// Must call spaces() before calling this,
-/// because we can't make it optional() if spaces() consumes input
-/// TODO: fix that, so we can have spaces() at the start of this properly. (see below)
+#[cfg(test)]
 fn dialog_lines<'a, Input>() -> impl Parser<Input, Output = Vec<DialogLine>>
 where
-    Input: RangeStream<Token = char, Range = &'a str>,
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    many1::<Vec<DialogLine>, _, _>(dialog_line().skip(spaces().skip(many::<(), _, _>(choice((
-        single_line_comment(),
-        multi_line_comment(),
-    ))))))
+    body().map(|body| body.dialog_lines)
 }
 
-fn divert<'a, Input>() -> impl Parser<Input, Output = Divert>
+/// The span runs from the `->` to the end of its line
+fn divert<'a, Input>() -> impl Parser<Input, Output = Spanned<Divert>>
 where
-    Input: RangeStream<Token = char, Range = &'a str>,
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    spaces().with(
-        string("->")
-            .skip(spaces())
-            .with(rest_of_the_line())
-            .map(|s| Divert { knot_title: s }),
+    whitespace().with(
+        spanned(
+            string("->").with(choice((
+                // `->-> elsewhere` would go on to another tunnel, which isn't supported
+                attempt(string("->"))
+                    .skip(inline_spaces())
+                    .skip(not_followed_by(satisfy(|c| c != '\n' && c != '\r')))
+                    .skip(optional(newline_character()))
+                    .map(|_| Divert::TunnelReturn),
+                whitespace()
+                    .with(rest_of_the_line())
+                    .map(|s| Divert::from(s.as_str())),
+            ))),
+        )
+        .map(|(divert, span)| Spanned::new(divert, span)),
     )
 }
 
 fn parse_choice<'a, Input>() -> impl Parser<Input, Output = Choice>
 where
-    Input: RangeStream<Token = char, Range = &'a str>,
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    char('+')
-        .skip(spaces())
-        .with(rest_of_the_line())
-        .skip(spaces())
-        .and(optional(dialog_lines()))
-        .and(divert())
-        .map(|((title, lines), divert)| Choice {
+    spanned(
+        char('+')
+            .skip(whitespace())
+            .with(rest_of_the_line())
+            .skip(whitespace())
+            .and(optional(body()))
+            .and(divert()),
+    )
+    .map(|(((title, body), divert), span)| {
+        let mut body = body.unwrap_or_default();
+        // a choice has no tags of its own, so they go on its first line
+        if let Some(first_line) = body.dialog_lines.first_mut() {
+            body.tags.append(&mut first_line.tags);
+            first_line.tags = body.tags;
+        }
+        Choice {
             text: title,
-            dialog_lines: lines.unwrap_or_default(),
+            dialog_lines: body.dialog_lines,
+            statements: body.statements,
             divert,
-        })
+            span,
+        }
+    })
 }
 
-fn knot_title<'a, Input>() -> impl Parser<Input, Output = String>
+/// `=== title ===`, `=== title(a, b) ===` or `=== function title(a, b) ===`,
+/// as (whether it's a function, title, parameters)
+fn knot_header<'a, Input>() -> impl Parser<Input, Output = (bool, String, Vec<String>)>
 where
     Input: RangeStream<Token = char, Range = &'a str>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    spaces().with(
+    whitespace().with(
         string("==")
             .skip(many::<String, _, _>(char('=')))
-            .skip(spaces())
-            .with(many1::<String, _, _>(satisfy(|c| {
-                c != '\n' && c != '\r' && c != ' '
+            .skip(inline_spaces())
+            .with(optional(attempt(keyword("function"))).map(|f| f.is_some()))
+            .and(many1::<String, _, _>(satisfy(|c| {
+                c != '\n' && c != '\r' && c != ' ' && c != '\t' && c != '/' && c != '('
             })))
-            .skip(optional(char(' ').and(string("==").skip(many::<
-                String,
-                _,
-                _,
-            >(
-                char('=')
-            )))))
-            .skip(newline_character()),
+            .and(optional(parameters()).map(Option::unwrap_or_default))
+            .skip(inline_spaces())
+            .skip(optional(
+                attempt(string("=="))
+                    .skip(many::<String, _, _>(char('=')))
+                    .skip(inline_spaces()),
+            ))
+            .skip(newline_character())
+            .map(|((is_function, title), parameters)| (is_function, title, parameters)),
     )
 }
 
-fn knot_end<'a, Input>() -> impl Parser<Input, Output = KnotEnding>
+#[cfg(test)]
+fn knot_title<'a, Input>() -> impl Parser<Input, Output = String>
 where
     Input: RangeStream<Token = char, Range = &'a str>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    spaces().with(choice((
+    knot_header().map(|(_, title, _)| title)
+}
+
+fn knot_end<'a, Input>() -> impl Parser<Input, Output = KnotEnding>
+where
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    whitespace().with(choice((
         many1::<Vec<Choice>, _, _>(parse_choice()).map(KnotEnding::CHOICES),
         divert().map(KnotEnding::DIVERT),
+        value(KnotEnding::IMPLICIT),
     )))
 }
 
 fn knot_without_title<'a, Input>() -> impl Parser<Input, Output = Knot>
 where
-    Input: RangeStream<Token = char, Range = &'a str>,
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    spaces()
-        .with(optional(dialog_lines()))
+    whitespace()
+        .with(position())
+        .and(optional(body()))
         .and(knot_end())
-        .map(|(lines, ending)| Knot {
-            title: "INTRO".to_string(),
-            dialog_lines: lines.unwrap_or_default(),
-            ending,
+        .and(position())
+        .skip(whitespace())
+        .map(|(((start, body), ending), end)| {
+            let body = body.unwrap_or_default();
+            Knot {
+                title: "INTRO".to_string(),
+                parameters: vec![],
+                is_function: false,
+                tags: body.tags,
+                dialog_lines: body.dialog_lines,
+                statements: body.statements,
+                ending,
+                span: Span::new(start, end),
+            }
         })
 }
 
 fn knot<'a, Input>() -> impl Parser<Input, Output = Knot>
 where
-    Input: RangeStream<Token = char, Range = &'a str>,
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    spaces().with(
-        knot_title()
-            .and(knot_without_title())
-            .map(|(knot_title, knot)| Knot {
-                title: knot_title,
-                dialog_lines: knot.dialog_lines,
-                ending: knot.ending,
-            }),
+    spanned(knot_header()).and(knot_without_title()).map(
+        |(((is_function, title, parameters), header_span), knot)| Knot {
+            title,
+            parameters,
+            is_function,
+            span: header_span.to(knot.span),
+            ..knot
+        },
     )
 }
 
+/// What can come at the top of a story, before its first line
+enum HeaderLine {
+    Variable(String, Expression),
+    External(String, Vec<String>),
+    Tags(Vec<Tag>),
+}
+
 fn story<'a, Input>() -> impl Parser<Input, Output = Story>
 where
-    Input: RangeStream<Token = char, Range = &'a str>,
+    Input: RangeStream<Token = char, Range = &'a str, Position = Location>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    knot_without_title().and(many(knot())).map(
-        |(intro_knot, mut other_knots): (Knot, Vec<Knot>)| {
+    many::<Vec<_>, _, _>(choice((
+        global_variable().map(|(name, value)| HeaderLine::Variable(name, value)),
+        external_declaration().map(|(name, parameters)| HeaderLine::External(name, parameters)),
+        attempt(whitespace().with(tag_line())).map(HeaderLine::Tags),
+    )))
+    .and(knot_without_title())
+    .and(many(knot()))
+    .map(
+        |((header, intro_knot), mut other_knots): ((Vec<_>, Knot), Vec<Knot>)| {
             let mut knots: Vec<Knot> = vec![intro_knot];
             knots.append(other_knots.as_mut());
-            Story {
-                knots: knots
-                    .into_iter()
-                    .map(|knot: Knot| (knot.title.clone(), knot))
-                    .collect(),
+            let mut global_tags = vec![];
+            let mut variables = BTreeMap::new();
+            let mut externals = BTreeMap::new();
+            for line in header {
+                match line {
+                    HeaderLine::Variable(name, value) => {
+                        variables.insert(name, value);
+                    }
+                    HeaderLine::External(name, parameters) => {
+                        externals.insert(name, parameters);
+                    }
+                    HeaderLine::Tags(mut tags) => global_tags.append(&mut tags),
+                }
             }
+            let mut story = Story {
+                global_tags,
+                variables,
+                externals,
+                knots,
+            };
+            story.resolve_divert_variables();
+            story
         },
     )
 }
 
 pub fn parse_story(text: &str) -> Story {
-    story().easy_parse(text).unwrap().0
+    parse_story_in_file(text, FileId::default())
+}
+
+/// Like `parse_story`, but every span points into `file`
+pub fn parse_story_in_file(text: &str, file: FileId) -> Story {
+    story().easy_parse(story_stream(text, file)).unwrap().0
 }
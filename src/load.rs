@@ -0,0 +1,718 @@
+use crate::{
+    BinaryOperator, Choice, DialogLine, Divert, Expression, Knot, KnotEnding, Span, Spanned,
+    Statement, StatementKind, Story, Tag, UnaryOperator, INK_VERSION,
+};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The oldest version of ink's runtime format that `load_ink_json` reads
+pub const OLDEST_INK_VERSION: u32 = 19;
+
+/// Ink's JSON that couldn't be loaded at all.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LoadError {
+    /// not JSON, or not shaped like a compiled ink story
+    Malformed(String),
+    UnsupportedInkVersion(u64),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Malformed(reason) => write!(f, "not a compiled ink story: {}", reason),
+            LoadError::UnsupportedInkVersion(version) => write!(
+                f,
+                "ink version {} isn't supported (only {} to {})",
+                version, OLDEST_INK_VERSION, INK_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Something in ink's JSON that a `Story` has no way to hold, so it was left out
+/// (or loaded as the nearest thing that fits).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Unrepresentable {
+    /// where it is in the runtime's paths, like `paris.0.c-1`
+    path: String,
+    construct: String,
+}
+
+impl Unrepresentable {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// what it was, like `glue` or `once-only choice`
+    pub fn construct(&self) -> &str {
+        &self.construct
+    }
+}
+
+/// Loads a story from the JSON that inklecate (or `compile_story`) writes.
+///
+/// Stitches become knots titled `knot.stitch`, and `~ x = x + 1` becomes `~ x += 1`.
+/// Knots come in the order the JSON has them in, which for inklecate is source order.
+/// A call to an ink function takes as many arguments as the function's container
+/// starts by assigning, and a knot that returns is loaded as a function.
+/// Only the `EXTERNAL`s that are called are in ink's JSON, and without the names
+/// of their parameters, so those are numbered (`arg1`, `arg2`, ...).
+pub fn load_ink_json(json: &str) -> Result<(Story, Vec<Unrepresentable>), LoadError> {
+    let json: Value =
+        serde_json::from_str(json).map_err(|error| LoadError::Malformed(error.to_string()))?;
+
+    let version = json
+        .get("inkVersion")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| LoadError::Malformed("no inkVersion".to_string()))?;
+    if version < OLDEST_INK_VERSION.into() || version > INK_VERSION.into() {
+        return Err(LoadError::UnsupportedInkVersion(version));
+    }
+
+    let root = json
+        .get("root")
+        .filter(|root| root.is_array())
+        .ok_or_else(|| LoadError::Malformed("no root container".to_string()))?;
+
+    let mut loader = Loader {
+        root,
+        unrepresentable: vec![],
+        externals: BTreeMap::new(),
+    };
+    let story = loader.story();
+    Ok((story, loader.unrepresentable))
+}
+
+struct Loader<'a> {
+    root: &'a Value,
+    unrepresentable: Vec<Unrepresentable>,
+    /// how many arguments each external function is called with
+    externals: BTreeMap<String, usize>,
+}
+
+impl<'a> Loader<'a> {
+    fn story(&mut self) -> Story {
+        let mut story = Story::default();
+
+        if let Some(start) = self.root.get(0) {
+            let mut intro = self.knot("INTRO", start, vec!["0".to_string()]);
+            // tags at the very start of the story are its global tags
+            story.global_tags = std::mem::take(&mut intro.tags);
+            // `compile_story` keeps the top of the story in a knot called INTRO
+            let goes_to_intro = intro.dialog_lines.is_empty()
+                && intro.statements.is_empty()
+                && intro.ending == KnotEnding::DIVERT(Divert::Knot("INTRO".to_string()).into());
+            if !goes_to_intro {
+                story.knots.push(intro);
+            }
+        }
+
+        // INTRO comes first, as it does in a parsed story
+        let (intro, named): (Vec<_>, Vec<_>) =
+            named_content(self.root).partition(|(name, _)| *name == "INTRO");
+        for (name, content) in intro.into_iter().chain(named) {
+            if name == "global decl" {
+                story.variables = self.global_variables(content);
+            } else {
+                self.knots(name, content, &mut story.knots);
+            }
+        }
+
+        for (name, count) in std::mem::take(&mut self.externals) {
+            let parameters = (1..=count).map(|n| format!("arg{}", n)).collect();
+            story.externals.insert(name, parameters);
+        }
+        story
+    }
+
+    /// The knot, followed by its stitches
+    fn knots(&mut self, title: &str, container: &'a Value, knots: &mut Vec<Knot>) {
+        knots.push(self.knot(title, container, vec![title.to_string()]));
+
+        for (name, content) in named_content(container) {
+            if !is_weave_name(name) {
+                let stitch = format!("{}.{}", title, name);
+                let path = vec![title.to_string(), name.to_string()];
+                knots.push(self.knot(&stitch, content, path));
+            }
+        }
+    }
+
+    fn knot(&mut self, title: &str, container: &'a Value, path: Vec<String>) -> Knot {
+        let mut content = Content::default();
+        self.container(container, &path, &mut content);
+        let is_function = content.returns;
+        let mut body = content.finish(self, &path);
+
+        // a function that runs out returns nothing, without having to say so
+        if is_function && body.ending == KnotEnding::IMPLICIT {
+            let ends_with_return = matches!(
+                body.statements.last(),
+                Some(statement) if statement.kind == StatementKind::Return(None)
+                    && statement.before_line == body.dialog_lines.len()
+            );
+            if ends_with_return {
+                body.statements.pop();
+            }
+        }
+
+        Knot {
+            title: title.to_string(),
+            parameters: body.parameters,
+            is_function,
+            tags: body.tags,
+            dialog_lines: body.dialog_lines,
+            statements: body.statements,
+            ending: body.ending,
+            span: Span::default(),
+        }
+    }
+
+    fn global_variables(&mut self, container: &'a Value) -> BTreeMap<String, Expression> {
+        let path = vec!["global decl".to_string()];
+        let mut content = Content::default();
+        let mut variables = BTreeMap::new();
+        for item in container_items(container) {
+            match item.get("VAR=").and_then(Value::as_str) {
+                Some(name) => {
+                    if let Some(value) = self.operand(&mut content, &path, "VAR=") {
+                        variables.insert(name.to_string(), value);
+                    }
+                }
+                None => self.item(item, &path, &mut content),
+            }
+        }
+        variables
+    }
+
+    fn report(&mut self, path: &[String], construct: &str) {
+        self.unrepresentable.push(Unrepresentable {
+            path: path.join("."),
+            construct: construct.to_string(),
+        });
+    }
+
+    /// The value on top of the evaluation stack, which is reported missing
+    /// (along with whatever needed it) if there isn't one
+    fn operand(
+        &mut self,
+        content: &mut Content,
+        path: &[String],
+        command: &str,
+    ) -> Option<Expression> {
+        let operand = content.stack.pop();
+        if operand.is_none() {
+            self.report(path, &format!("{} without a value", command_name(command)));
+        }
+        operand
+    }
+
+    /// Calls `name` with the top `count` values of the evaluation stack
+    fn call(&mut self, content: &mut Content, path: &[String], name: &str, count: usize) {
+        if content.stack.len() < count {
+            self.report(path, &format!("{}() without its arguments", name));
+        }
+        let at = content.stack.len().saturating_sub(count);
+        let arguments = content.stack.split_off(at);
+        content
+            .stack
+            .push(Expression::Call(name.to_string(), arguments));
+    }
+
+    fn container(&mut self, container: &'a Value, path: &[String], content: &mut Content) {
+        for (index, item) in container_items(container).iter().enumerate() {
+            if item.is_array() {
+                let mut inner = path.to_vec();
+                inner.push(index.to_string());
+                self.container(item, &inner, content);
+            } else {
+                self.item(item, path, content);
+            }
+        }
+
+        for (name, _) in named_content(container) {
+            if name.starts_with("g-") {
+                self.report(path, "gather");
+            }
+        }
+    }
+
+    fn item(&mut self, item: &'a Value, path: &[String], content: &mut Content) {
+        match item {
+            Value::String(s) => self.command(s, path, content),
+            Value::Number(n) => content.stack.push(match n.as_i64() {
+                Some(n) => Expression::Int(n as i32),
+                None => Expression::Float(n.as_f64().unwrap_or_default()),
+            }),
+            Value::Bool(b) => content.stack.push(Expression::Bool(*b)),
+            Value::Object(object) => self.object(object, path, content),
+            Value::Null | Value::Array(_) => {}
+        }
+    }
+
+    fn command(&mut self, command: &str, path: &[String], content: &mut Content) {
+        if let Some(text) = command.strip_prefix('^') {
+            content.text(text);
+            return;
+        }
+
+        match command {
+            "\n" => content.end_line(),
+            "#" => content.tag = Some(String::new()),
+            "/#" => {
+                if let Some(tag) = content.tag.take() {
+                    content.push_tag(tag.as_str().into());
+                }
+            }
+            // a function's return value is whatever's left to return
+            "ev" | "/ev" | "nop" | "void" => {}
+            "~ret" => {
+                let value = content.stack.pop();
+                content.statement(StatementKind::Return(value));
+                content.returns = true;
+            }
+            "str" => content.string = Some(String::new()),
+            "/str" => {
+                if let Some(string) = content.string.take() {
+                    content.stack.push(Expression::Str(string));
+                }
+            }
+            "out" => {
+                if let Some(expression) = self.operand(content, path, command) {
+                    content.text(&format!("{{{}}}", expression));
+                }
+            }
+            "pop" => {
+                if let Some(expression) = self.operand(content, path, command) {
+                    content.statement(StatementKind::Expression(expression));
+                }
+            }
+            "end" => content.divert(Divert::End, self, path),
+            "done" => content.divert(Divert::Done, self, path),
+            "->->" => content.divert(Divert::TunnelReturn, self, path),
+            "_" | "!" => {
+                if let Some(operand) = self.operand(content, path, command) {
+                    let operator = match command {
+                        "_" => UnaryOperator::Negate,
+                        _ => UnaryOperator::Not,
+                    };
+                    content
+                        .stack
+                        .push(Expression::Unary(operator, Box::new(operand)));
+                }
+            }
+            "turn" => self.call(content, path, "TURNS", 0),
+            "turns" => self.call(content, path, "TURNS_SINCE", 1),
+            "readc" => self.call(content, path, "READ_COUNT", 1),
+            "choiceCnt" => self.call(content, path, "CHOICE_COUNT", 0),
+            "rnd" => self.call(content, path, "RANDOM", 2),
+            "srnd" => self.call(content, path, "SEED_RANDOM", 1),
+            "INT" | "FLOAT" | "FLOOR" | "CEILING" => self.call(content, path, command, 1),
+            "POW" | "MIN" | "MAX" => self.call(content, path, command, 2),
+            _ => match binary_operator(command) {
+                Some(operator) => {
+                    if let Some(right) = self.operand(content, path, command) {
+                        if let Some(left) = self.operand(content, path, command) {
+                            content.stack.push(Expression::Binary(
+                                Box::new(left),
+                                operator,
+                                Box::new(right),
+                            ));
+                        }
+                    }
+                }
+                None => self.report(path, command_name(command)),
+            },
+        }
+    }
+
+    fn object(&mut self, object: &'a Map<String, Value>, path: &[String], content: &mut Content) {
+        let string = |key: &str| object.get(key).and_then(Value::as_str);
+
+        if let Some(target) = string("^->") {
+            // inklecate's bookkeeping for choice text
+            if !target.contains('$') {
+                content
+                    .stack
+                    .push(Expression::DivertTarget(target.to_string()));
+            }
+        } else if let Some(name) = string("VAR?").or_else(|| string("CNT?")) {
+            content.stack.push(Expression::Variable(name.to_string()));
+        } else if let Some(name) = string("temp=").or_else(|| string("VAR=")) {
+            // inklecate's `$r` temps hold bookkeeping that was never pushed
+            if name.starts_with('$') {
+                return;
+            }
+            let name = name.to_string();
+            let redeclared = object.contains_key("VAR=") || object.contains_key("re");
+            // a function (or knot) starts by taking its arguments off the stack
+            if content.stack.is_empty() && !redeclared && content.is_empty() {
+                content.parameters.insert(0, name);
+                return;
+            }
+            let value = match self.operand(content, path, if redeclared { "=" } else { "temp=" }) {
+                Some(value) => value,
+                None => return,
+            };
+            content.statement(match value {
+                _ if !redeclared => StatementKind::TempDeclaration(name, value),
+                Expression::Binary(left, BinaryOperator::Add, right)
+                    if *left == Expression::Variable(name.clone()) =>
+                {
+                    StatementKind::AddAssignment(name, *right)
+                }
+                Expression::Binary(left, BinaryOperator::Subtract, right)
+                    if *left == Expression::Variable(name.clone()) =>
+                {
+                    StatementKind::SubtractAssignment(name, *right)
+                }
+                _ => StatementKind::Assignment(name, value),
+            });
+        } else if let Some(target) = string("->") {
+            self.divert_object(object, target, path, content);
+        } else if let Some(target) = string("*") {
+            let flags = object.get("flg").and_then(Value::as_u64).unwrap_or(0);
+            self.choice_point(target, flags, path, content);
+        } else if let Some(tag) = string("#") {
+            content.push_tag(tag.into());
+        } else if let Some(name) = string("f()") {
+            let count = match self.resolve(&[], name) {
+                Some(function) => parameters(function).len(),
+                None => {
+                    self.report(path, "call to a missing function");
+                    0
+                }
+            };
+            self.call(content, path, name, count);
+        } else if let Some(name) = string("x()") {
+            let count = object.get("exArgs").and_then(Value::as_u64).unwrap_or(0) as usize;
+            self.externals.insert(name.to_string(), count);
+            self.call(content, path, name, count);
+        } else if object.contains_key("->t->") || object.contains_key("f->") {
+            self.report(path, "tunnel");
+        } else if object.contains_key("list") {
+            self.report(path, "list");
+        } else if !object.contains_key("#f") && !object.contains_key("#n") {
+            self.report(path, "unknown object");
+        }
+    }
+
+    fn divert_object(
+        &mut self,
+        object: &Map<String, Value>,
+        target: &str,
+        path: &[String],
+        content: &mut Content,
+    ) {
+        if object.contains_key("c") {
+            self.report(path, "conditional divert");
+        } else if object.contains_key("var") {
+            // the end of the text of an inklecate choice
+            if !target.starts_with('$') {
+                content.divert(Divert::Variable(target.to_string()), self, path);
+            }
+        } else if target.starts_with('.') {
+            match self.resolve(path, target) {
+                // the text of an inklecate choice, which lives in `s`
+                Some(container) if target.ends_with(".s") => {
+                    for item in container_items(container) {
+                        if let Some(text) = item.as_str().and_then(|s| s.strip_prefix('^')) {
+                            content.text(text);
+                        }
+                    }
+                }
+                _ => self.report(path, "divert within a knot"),
+            }
+        } else if target.split('.').any(is_weave_name) {
+            self.report(path, "divert to a gather or choice");
+        } else {
+            content.divert(Divert::Knot(target.to_string()), self, path);
+        }
+    }
+
+    fn choice_point(&mut self, target: &str, flags: u64, path: &[String], content: &mut Content) {
+        let choice_only = if flags & 0x4 != 0 {
+            content.pop_string()
+        } else {
+            String::new()
+        };
+        let start = if flags & 0x2 != 0 {
+            content.pop_string()
+        } else {
+            String::new()
+        };
+        if flags & 0x1 != 0 {
+            content.stack.pop();
+            self.report(path, "conditional choice");
+        }
+        if flags & 0x8 != 0 {
+            self.report(path, "fallback choice");
+        }
+        if flags & 0x10 != 0 {
+            self.report(path, "once-only choice");
+        }
+        let text = format!("{}{}", start, choice_only).trim().to_string();
+
+        // inklecate writes the path relative to the choice point when that's shorter
+        let choice_path = absolute_path(path, target);
+        let mut body = Content::default();
+        match self.resolve(path, target) {
+            Some(container) => self.container(container, &choice_path, &mut body),
+            None => self.report(path, "choice without a container"),
+        }
+        let mut body = body.finish(self, &choice_path);
+        if !body.parameters.is_empty() {
+            self.report(&choice_path, "choice with parameters");
+        }
+
+        // the choice's text is printed when it's picked, which `Choice` does by itself
+        if body.dialog_lines.first().map(|line| line.text.as_str()) == Some(text.as_str()) {
+            body.dialog_lines.remove(0);
+            for statement in &mut body.statements {
+                statement.before_line = statement.before_line.saturating_sub(1);
+            }
+        }
+        let divert = match body.ending {
+            KnotEnding::DIVERT(divert) => divert,
+            _ => {
+                self.report(&choice_path, "choice that doesn't end in a divert");
+                Divert::Done.into()
+            }
+        };
+
+        content.choices.push(Choice {
+            text,
+            dialog_lines: body.dialog_lines,
+            statements: body.statements,
+            divert,
+            span: Span::default(),
+        });
+    }
+
+    /// Finds a container by its runtime path. Relative paths start from the container at `from`.
+    fn resolve(&self, from: &[String], target: &str) -> Option<&'a Value> {
+        absolute_path(from, target)
+            .iter()
+            .try_fold(self.root, |container, component| {
+                let items = container.as_array()?;
+                match component.parse::<usize>() {
+                    Ok(index) => items.get(index),
+                    Err(_) => named_content(container)
+                        .find(|(name, _)| *name == component.as_str())
+                        .map(|(_, content)| content),
+                }
+            })
+    }
+}
+
+/// What's been read out of a container so far
+#[derive(Default)]
+struct Content {
+    tags: Vec<Tag>,
+    dialog_lines: Vec<DialogLine>,
+    statements: Vec<Statement>,
+    choices: Vec<Choice>,
+    divert: Option<Divert>,
+    line: Option<DialogLine>,
+    /// the evaluation stack
+    stack: Vec<Expression>,
+    /// inside `str` and `/str`
+    string: Option<String>,
+    /// inside `#` and `/#`
+    tag: Option<String>,
+    /// temps assigned from the arguments, before anything else
+    parameters: Vec<String>,
+    /// whether there's a `~ret`, which only a function has
+    returns: bool,
+}
+
+struct Body {
+    parameters: Vec<String>,
+    tags: Vec<Tag>,
+    dialog_lines: Vec<DialogLine>,
+    statements: Vec<Statement>,
+    ending: KnotEnding,
+}
+
+impl Content {
+    /// Nothing's been read out yet, apart from parameters
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+            && self.dialog_lines.is_empty()
+            && self.statements.is_empty()
+            && self.choices.is_empty()
+            && self.divert.is_none()
+            && self.line.is_none()
+            && self.string.is_none()
+            && self.tag.is_none()
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some(tag) = &mut self.tag {
+            tag.push_str(text);
+        } else if let Some(string) = &mut self.string {
+            string.push_str(text);
+        } else {
+            let line = self.line.get_or_insert_with(DialogLine::default);
+            line.text.push_str(text);
+        }
+    }
+
+    fn push_tag(&mut self, tag: Tag) {
+        match &mut self.line {
+            Some(line) => line.tags.push(tag),
+            None if self.dialog_lines.is_empty() => self.tags.push(tag),
+            None => self
+                .line
+                .get_or_insert_with(DialogLine::default)
+                .tags
+                .push(tag),
+        }
+    }
+
+    fn end_line(&mut self) {
+        if let Some(mut line) = self.line.take() {
+            line.text = line.text.trim().to_string();
+            self.dialog_lines.push(line);
+        }
+    }
+
+    fn statement(&mut self, kind: StatementKind) {
+        self.end_line();
+        self.statements
+            .push(Statement::new(self.dialog_lines.len(), kind));
+    }
+
+    fn divert(&mut self, divert: Divert, loader: &mut Loader<'_>, path: &[String]) {
+        self.end_line();
+        match self.divert {
+            Some(_) => loader.report(path, "content after a divert"),
+            None => self.divert = Some(divert),
+        }
+    }
+
+    fn pop_string(&mut self) -> String {
+        match self.stack.pop() {
+            Some(Expression::Str(s)) => s,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        }
+    }
+
+    fn finish(mut self, loader: &mut Loader<'_>, path: &[String]) -> Body {
+        self.end_line();
+        let ending = match (self.choices.is_empty(), self.divert) {
+            (true, Some(divert)) => KnotEnding::DIVERT(Spanned::from(divert)),
+            (true, None) => KnotEnding::IMPLICIT,
+            (false, divert) => {
+                if divert.is_some() {
+                    loader.report(path, "divert after choices");
+                }
+                KnotEnding::CHOICES(self.choices)
+            }
+        };
+        Body {
+            parameters: self.parameters,
+            tags: self.tags,
+            dialog_lines: self.dialog_lines,
+            statements: self.statements,
+            ending,
+        }
+    }
+}
+
+/// A container's content, without the terminator at its end
+fn container_items(container: &Value) -> &[Value] {
+    match container.as_array().map(Vec::as_slice) {
+        Some([items @ .., last]) if last.is_null() || last.is_object() => items,
+        Some(items) => items,
+        None => &[],
+    }
+}
+
+/// The containers named in a container's terminator, like knots in the root
+/// A runtime path from the root. Relative paths, like `.^.c-0`, start from the
+/// container at `from`.
+fn absolute_path(from: &[String], target: &str) -> Vec<String> {
+    let mut components = from.to_vec();
+    let mut parts = target.split('.').peekable();
+    if target.starts_with('.') {
+        parts.next();
+        // the first `^` is the container the divert is in
+        parts.next_if_eq(&"^");
+        while parts.next_if_eq(&"^").is_some() {
+            components.pop();
+        }
+    } else {
+        components.clear();
+    }
+    components.extend(parts.map(str::to_string));
+    components
+}
+
+fn named_content(container: &Value) -> impl Iterator<Item = (&str, &Value)> {
+    container
+        .as_array()
+        .and_then(|items| items.last())
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .filter(|(name, content)| !name.starts_with('#') && content.is_array())
+        .map(|(name, content)| (name.as_str(), content))
+}
+
+/// The temps a container starts by assigning from its arguments, in the order
+/// they're passed
+fn parameters(container: &Value) -> Vec<String> {
+    let mut parameters: Vec<String> = container_items(container)
+        .iter()
+        .map_while(|item| item.get("temp=").filter(|_| item.get("re").is_none()))
+        .filter_map(|name| name.as_str().map(str::to_string))
+        .collect();
+    parameters.reverse();
+    parameters
+}
+
+/// `c-0` for a choice, `g-0` for a gather, `s` for a choice's text
+fn is_weave_name(name: &str) -> bool {
+    name.starts_with("c-") || name.starts_with("g-") || name == "s" || name.starts_with('$')
+}
+
+fn binary_operator(command: &str) -> Option<BinaryOperator> {
+    Some(match command {
+        "||" => BinaryOperator::Or,
+        "&&" => BinaryOperator::And,
+        "==" => BinaryOperator::Equal,
+        "!=" => BinaryOperator::NotEqual,
+        "<" => BinaryOperator::Less,
+        "<=" => BinaryOperator::LessOrEqual,
+        ">" => BinaryOperator::Greater,
+        ">=" => BinaryOperator::GreaterOrEqual,
+        "+" => BinaryOperator::Add,
+        "-" => BinaryOperator::Subtract,
+        "*" => BinaryOperator::Multiply,
+        "/" => BinaryOperator::Divide,
+        "%" => BinaryOperator::Modulo,
+        _ => return None,
+    })
+}
+
+/// A readable name for a runtime command, for reporting it
+fn command_name(command: &str) -> &str {
+    match command {
+        "<>" => "glue",
+        "thread" => "thread",
+        "visit" | "seq" => "sequence",
+        "du" => "duplicate",
+        "void" => "void",
+        "VAR=" | "=" => "assignment",
+        "temp=" => "temp",
+        "out" => "printed expression",
+        "pop" => "expression statement",
+        "_" | "!" => "unary operator",
+        _ => command,
+    }
+}
@@ -0,0 +1,66 @@
+/// A small random number generator (SplitMix64) that gives the same numbers from the
+/// same seed on every platform, so that playthroughs can be replayed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub(crate) struct Random {
+    /// what it was seeded with, which shuffles are worked out from
+    seed: u64,
+    state: u64,
+}
+
+impl Random {
+    pub(crate) fn new(seed: u64) -> Self {
+        Random { seed, state: seed }
+    }
+
+    /// Carries on a sequence from where `state` says it was up to
+    pub(crate) fn resume(seed: u64, state: u64) -> Self {
+        Random { seed, state }
+    }
+
+    /// Seeded from the clock, for when the host doesn't pick a seed
+    pub(crate) fn from_time() -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Random::new(now.as_nanos() as u64)
+    }
+
+    pub(crate) fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Where the sequence is up to, to save and restore
+    pub(crate) fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number from `min` to `max`, including both
+    pub(crate) fn between(&mut self, min: i32, max: i32) -> i32 {
+        let range = (i64::from(max) - i64::from(min) + 1) as u64;
+        (i64::from(min) + (self.next() % range) as i64) as i32
+    }
+
+    /// Which of `len` things comes `position`th in the `cycle`th shuffle at `site`.
+    /// Like ink, each shuffle is worked out from the seed, the site and the cycle,
+    /// so every thing comes up once a cycle, and the sequence carries on untouched.
+    pub(crate) fn shuffled(&self, site: &str, cycle: usize, position: usize, len: usize) -> usize {
+        let site_hash = site.bytes().fold(0u64, |hash, byte| {
+            hash.wrapping_mul(31).wrapping_add(u64::from(byte))
+        });
+        let mut random = Random::new(self.seed ^ site_hash.wrapping_add(cycle as u64));
+        let mut unpicked: Vec<usize> = (0..len).collect();
+        for _ in 0..position {
+            let picked = (random.next() % unpicked.len() as u64) as usize;
+            unpicked.remove(picked);
+        }
+        unpicked[(random.next() % unpicked.len() as u64) as usize]
+    }
+}
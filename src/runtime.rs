@@ -0,0 +1,1174 @@
+use crate::expression::parse_inline_expression;
+use crate::external::{BoxedExternal, ExternalFunction};
+use crate::history::History;
+use crate::random::Random;
+use crate::tag_handlers::{TagEvent, TagHandler};
+use crate::tags::find_tag;
+use crate::value::Value;
+use crate::{
+    DialogLine, Divert, Expression, Knot, KnotEnding, Statement, StatementKind, Story, Tag,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Something that went wrong while running a story.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuntimeError {
+    /// `continue_line` was called when `can_continue` was false
+    CannotContinue,
+    /// `choose` was given an index past the end of `current_choices`
+    NoSuchChoice(usize),
+    UnknownKnot(String),
+    UnknownVariable(String),
+    UnknownFunction(String),
+    /// an expression from the host that doesn't parse
+    InvalidExpression(String),
+    /// `rewind` was asked to go back more choices than it kept
+    CannotRewind(usize),
+    /// `remove_flow` was given a flow that doesn't exist
+    UnknownFlow(String),
+    /// like adding a number to a divert target, or dividing by zero
+    InvalidOperation(String),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::CannotContinue => write!(f, "the story can't continue"),
+            RuntimeError::NoSuchChoice(index) => write!(f, "there's no choice {}", index),
+            RuntimeError::UnknownKnot(title) => write!(f, "there's no knot called {}", title),
+            RuntimeError::UnknownVariable(name) => write!(f, "there's no variable called {}", name),
+            RuntimeError::UnknownFunction(name) => write!(f, "there's no function called {}", name),
+            RuntimeError::InvalidExpression(source) => write!(f, "can't parse {}", source),
+            RuntimeError::CannotRewind(choices) => {
+                write!(f, "can't go back {} choices", choices)
+            }
+            RuntimeError::UnknownFlow(name) => write!(f, "there's no flow called {}", name),
+            RuntimeError::InvalidOperation(operation) => write!(f, "can't do {}", operation),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A line of the story's output, with the tags that go with it
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Line {
+    text: String,
+    tags: Vec<Tag>,
+    speaker: Option<String>,
+}
+
+impl Line {
+    pub(crate) fn new(text: &str, tags: Vec<Tag>, speaker: Option<String>) -> Self {
+        Line {
+            text: text.to_string(),
+            tags,
+            speaker,
+        }
+    }
+
+    /// without the speaker, if `Story::extract_speakers` split one off
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn speaker(&self) -> Option<&str> {
+        self.speaker.as_deref()
+    }
+
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        find_tag(&self.tags, name)
+    }
+}
+
+/// What an expression or function gave back, for the host
+#[derive(Debug, PartialEq, Clone)]
+pub struct Evaluated {
+    value: Value,
+    output: String,
+}
+
+impl Evaluated {
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Lines printed by functions on the way, one per line
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+}
+
+/// Where a flow is: the next step of a knot, or of one of its choices once it's picked
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Position {
+    pub(crate) knot: usize,
+    pub(crate) choice: Option<usize>,
+    pub(crate) step: usize,
+}
+
+/// A choice as it was offered, with its text worked out
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct PresentedChoice {
+    pub(crate) text: String,
+    pub(crate) knot: usize,
+    pub(crate) choice: usize,
+}
+
+/// The flow a runner starts in, which can't be removed
+pub const DEFAULT_FLOW: &str = "DEFAULT_FLOW";
+
+/// Everything about where the story is and what's happened, shared by all its flows
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct State {
+    pub(crate) globals: BTreeMap<String, Value>,
+    /// how many times each knot (or stitch) has been entered
+    pub(crate) visits: BTreeMap<String, u32>,
+    /// the turn each knot was last entered on
+    pub(crate) visited_on_turn: BTreeMap<String, u32>,
+    /// how many choices have been made
+    pub(crate) turn_index: u32,
+    pub(crate) random: Random,
+    /// how many times each `{a|b}` has been shown, by where it is
+    pub(crate) alternatives: BTreeMap<String, u32>,
+    /// the flow that's running, which isn't in `flows`
+    pub(crate) flow_name: String,
+    pub(crate) flow: Flow,
+    /// the other flows, waiting to be switched to
+    pub(crate) flows: BTreeMap<String, Flow>,
+}
+
+/// One thread of the story, with its own place in it
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct Flow {
+    /// `~ temp`s of the knot the flow is in
+    pub(crate) temps: BTreeMap<String, Value>,
+    /// `None` once the flow is waiting on a choice, or has ended
+    pub(crate) position: Option<Position>,
+    /// a new flow is at the start of the first knot, but only goes into it (and
+    /// counts the visit) once `continue_line` steps in
+    pub(crate) unentered: bool,
+    pub(crate) choices: Vec<PresentedChoice>,
+    /// a problem found after the last line was returned, for the next `continue_line`
+    pub(crate) pending_error: Option<RuntimeError>,
+    /// the picked choice's text as it was offered, to be printed first
+    pub(crate) choice_text: Option<String>,
+    /// lines printed by functions called from `~` lines, to be given out next
+    pub(crate) output: Vec<Line>,
+}
+
+/// One step of a knot or choice
+enum Step<'a> {
+    /// a picked choice prints its own text first
+    ChoiceText,
+    Line(&'a DialogLine),
+    Statement(&'a Statement),
+    /// past the last line, where the knot's ending (or the choice's divert) takes over
+    End,
+}
+
+/// Plays a story: lines come out of `continue_line` until the story runs out or
+/// stops at choices, and `choose` picks one of them to carry on.
+///
+/// ```
+/// use ink_parser::{parse_story, StoryRunner};
+///
+/// let story = parse_story("Hello!\n+ Hi\n    -> END\n");
+/// let mut runner = StoryRunner::new(&story);
+/// assert_eq!(runner.continue_line().unwrap().text(), "Hello!");
+/// assert_eq!(runner.current_choices(), vec!["Hi"]);
+/// runner.choose(0).unwrap();
+/// assert_eq!(runner.continue_line().unwrap().text(), "Hi");
+/// assert!(!runner.can_continue());
+/// ```
+pub struct StoryRunner<'a> {
+    pub(crate) story: &'a Story,
+    pub(crate) state: State,
+    externals: BTreeMap<String, BoxedExternal<'a>>,
+    observers: BTreeMap<String, Vec<Observer<'a>>>,
+    /// globals assigned since observers were last told, with their values before that
+    pub(crate) changed: BTreeMap<String, Value>,
+    pub(crate) history: History,
+    /// by the prefix of the tags they want, in the order they were added
+    pub(crate) tag_handlers: Vec<(String, TagHandler<'a>)>,
+    /// knots entered (and lines given out) since tag handlers were last called
+    pub(crate) tag_events: Vec<TagEvent>,
+}
+
+/// Called with a global's name, its old value and its new value
+type Observer<'a> = Box<dyn FnMut(&str, &Value, &Value) + 'a>;
+
+impl<'a> StoryRunner<'a> {
+    /// Starts at the story's first knot, which is `INTRO` for a parsed story.
+    pub fn new(story: &'a Story) -> Self {
+        let mut runner = StoryRunner {
+            story,
+            state: State {
+                random: Random::from_time(),
+                flow_name: DEFAULT_FLOW.to_string(),
+                ..State::default()
+            },
+            externals: BTreeMap::new(),
+            observers: BTreeMap::new(),
+            changed: BTreeMap::new(),
+            history: History::default(),
+            tag_handlers: vec![],
+            tag_events: vec![],
+        };
+
+        for (name, value) in &story.variables {
+            match runner.evaluate(value) {
+                Ok(value) => {
+                    runner.state.globals.insert(name.clone(), value);
+                }
+                Err(error) => runner.state.flow.pending_error = Some(error),
+            }
+        }
+        runner.start_flow();
+        runner
+    }
+
+    /// Puts the running flow at the start of the first knot, going into it
+    fn start_flow(&mut self) {
+        if !self.story.knots.is_empty() {
+            self.enter_knot(0);
+        }
+        if let Err(error) = self.follow_endings() {
+            self.state.flow.pending_error = Some(error);
+        }
+    }
+
+    pub fn story(&self) -> &'a Story {
+        self.story
+    }
+
+    /// True if `continue_line` has something to give back
+    pub fn can_continue(&self) -> bool {
+        self.state.flow.position.is_some()
+            || self.state.flow.pending_error.is_some()
+            || !self.state.flow.output.is_empty()
+    }
+
+    /// Runs the story up to its next line. Tag handlers, and then observers of any
+    /// globals that changed along the way, hear about it once it's done, even if
+    /// it fails. Logic that runs with no line after it before the choices (or the
+    /// end) gives an empty line, like in ink.
+    pub fn continue_line(&mut self) -> Result<Line, RuntimeError> {
+        let line = self.continue_step();
+        if let Ok(line) = &line {
+            self.history.record_line(line);
+        }
+        self.handle_tags(line.as_ref().ok());
+        self.notify_observers();
+        line
+    }
+
+    fn continue_step(&mut self) -> Result<Line, RuntimeError> {
+        if let Some(error) = self.state.flow.pending_error.take() {
+            self.state.flow.position = None;
+            return Err(error);
+        }
+        if !self.state.flow.output.is_empty() {
+            return Ok(self.state.flow.output.remove(0));
+        }
+        if self.state.flow.position.is_none() {
+            return Err(RuntimeError::CannotContinue);
+        }
+        if self.state.flow.unentered {
+            self.enter_knot(0);
+            self.follow_endings()?;
+        }
+
+        let line = match self.next_line() {
+            Ok(Some(line)) => line,
+            // like ink, statements that run before choices (or the end) with no line
+            // in between give an empty one, so `can_continue` was right to say yes
+            Ok(None) => Line::default(),
+            Err(error) => {
+                self.state.flow.position = None;
+                return Err(error);
+            }
+        };
+
+        self.tag_events.push(TagEvent::Line);
+        // so that choices (or the end) show up as soon as the last line is out
+        if let Err(error) = self.settle() {
+            self.state.flow.pending_error = Some(error);
+        }
+        Ok(line)
+    }
+
+    /// Runs statements and follows endings until there's a line, or the flow stops
+    fn next_line(&mut self) -> Result<Option<Line>, RuntimeError> {
+        let mut line = None;
+        while line.is_none() {
+            if !self.state.flow.output.is_empty() {
+                line = Some(self.state.flow.output.remove(0));
+                break;
+            }
+            let position = match self.state.flow.position {
+                Some(position) => position,
+                None => break,
+            };
+            match self.step(position) {
+                Step::ChoiceText => {
+                    self.advance();
+                    line = Some(Line {
+                        text: self.state.flow.choice_text.take().unwrap_or_default(),
+                        ..Line::default()
+                    });
+                }
+                Step::Line(dialog_line) => {
+                    self.advance();
+                    line = Some(self.render_line(dialog_line, &self.site(position))?);
+                }
+                Step::Statement(statement) => {
+                    self.advance();
+                    self.execute(&statement.kind)?;
+                }
+                Step::End => self.follow_ending(position)?,
+            }
+        }
+        Ok(line)
+    }
+
+    /// The text of each choice on offer, once the story can't continue
+    pub fn current_choices(&self) -> Vec<&str> {
+        self.state
+            .flow
+            .choices
+            .iter()
+            .map(|choice| choice.text.as_str())
+            .collect()
+    }
+
+    /// Picks one of `current_choices`, by its index
+    pub fn choose(&mut self, index: usize) -> Result<(), RuntimeError> {
+        let choice = self
+            .state
+            .flow
+            .choices
+            .get(index)
+            .cloned()
+            .ok_or(RuntimeError::NoSuchChoice(index))?;
+
+        self.history.record_choice_point(&self.state);
+        self.state.flow.choices.clear();
+        self.state.flow.choice_text = Some(choice.text);
+        self.state.turn_index += 1;
+        self.state.flow.position = Some(Position {
+            knot: choice.knot,
+            choice: Some(choice.choice),
+            step: 0,
+        });
+        Ok(())
+    }
+
+    /// Jumps to the start of a knot (or a stitch, like `paris.downtown`), whatever the
+    /// story was doing, with arguments for the knot's parameters. The choices on offer
+    /// go away, and it doesn't count as a turn.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner, Value};
+    ///
+    /// let story = parse_story("Hello.\n-> END\n=== chapter(number)\nChapter {number}.\n-> END\n");
+    /// let mut runner = StoryRunner::new(&story);
+    /// runner.choose_path("chapter", &[Value::Int(3)]).unwrap();
+    /// assert_eq!(runner.continue_line().unwrap().text(), "Chapter 3.");
+    /// ```
+    pub fn choose_path(&mut self, path: &str, arguments: &[Value]) -> Result<(), RuntimeError> {
+        let index = self.knot_index(path)?;
+        let knot = self.knot(index);
+        if knot.is_function {
+            return Err(RuntimeError::InvalidOperation(format!(
+                "-> {}, which is a function",
+                path
+            )));
+        }
+        if arguments.len() != knot.parameters.len() {
+            return Err(RuntimeError::InvalidOperation(format!(
+                "-> {} with {} arguments, not {}",
+                path,
+                arguments.len(),
+                knot.parameters.len()
+            )));
+        }
+
+        self.state.flow.choices.clear();
+        self.state.flow.choice_text = None;
+        self.state.flow.output.clear();
+        self.state.flow.pending_error = None;
+        self.enter_knot(index);
+        let arguments = knot
+            .parameters
+            .iter()
+            .cloned()
+            .zip(arguments.iter().cloned());
+        self.state.flow.temps.extend(arguments);
+        if let Err(error) = self.follow_endings() {
+            self.state.flow.pending_error = Some(error);
+        }
+        Ok(())
+    }
+
+    /// The name of the flow that's running
+    pub fn current_flow(&self) -> &str {
+        &self.state.flow_name
+    }
+
+    /// The names of all the flows, including the one that's running
+    pub fn flows(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.state.flows.keys().map(String::as_str).collect();
+        names.push(&self.state.flow_name);
+        names.sort_unstable();
+        names
+    }
+
+    /// Runs a different flow from here on: a thread of the story with its own place
+    /// in it, its own choices and its own temps, sharing globals, visit counts and
+    /// turns with the rest. A new flow starts at the first knot, and goes into it on
+    /// its first `continue_line` (unless `choose_path` sends it elsewhere before
+    /// then). The one that was running waits where it is to be switched back to.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner};
+    ///
+    /// let story = parse_story("Hello.\n-> END\n");
+    /// let mut runner = StoryRunner::new(&story);
+    /// runner.switch_flow("chat");
+    /// assert_eq!(runner.continue_line().unwrap().text(), "Hello.");
+    /// runner.switch_flow(ink_parser::DEFAULT_FLOW);
+    /// assert!(runner.can_continue());
+    /// ```
+    pub fn switch_flow(&mut self, name: &str) {
+        if name == self.state.flow_name {
+            return;
+        }
+        let flow = self.state.flows.remove(name);
+        let is_new = flow.is_none();
+        let previous = std::mem::replace(&mut self.state.flow, flow.unwrap_or_default());
+        let previous_name = std::mem::replace(&mut self.state.flow_name, name.to_string());
+        self.state.flows.insert(previous_name, previous);
+        if is_new && !self.story.knots.is_empty() {
+            self.state.flow.position = Some(Position {
+                knot: 0,
+                choice: None,
+                step: 0,
+            });
+            self.state.flow.unentered = true;
+        }
+        self.notify_observers();
+    }
+
+    /// Throws a flow away. Removing the flow that's running switches back to
+    /// `DEFAULT_FLOW`, which can't be removed.
+    pub fn remove_flow(&mut self, name: &str) -> Result<(), RuntimeError> {
+        if name == DEFAULT_FLOW {
+            return Err(RuntimeError::InvalidOperation(format!(
+                "removing {}",
+                DEFAULT_FLOW
+            )));
+        }
+        if name == self.state.flow_name {
+            self.switch_flow(DEFAULT_FLOW);
+        }
+        self.state
+            .flows
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| RuntimeError::UnknownFlow(name.to_string()))
+    }
+
+    /// Works out an expression, like `health > 50`, against the story as it is now.
+    /// It can read temps as well as globals, and call functions.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner, Value};
+    ///
+    /// let story = parse_story("VAR health = 80\nHello.\n");
+    /// let mut runner = StoryRunner::new(&story);
+    /// let result = runner.evaluate_expression("health > 50").unwrap();
+    /// assert_eq!(result.value(), &Value::Bool(true));
+    /// ```
+    pub fn evaluate_expression(&mut self, source: &str) -> Result<Evaluated, RuntimeError> {
+        let expression = parse_inline_expression(source.trim())
+            .ok_or_else(|| RuntimeError::InvalidExpression(source.to_string()))?;
+        self.evaluate_for_host(|runner| runner.evaluate(&expression))
+    }
+
+    /// Calls a function by name, like `{double(x)}` would: one the game has bound,
+    /// or else one written in ink
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Evaluated, RuntimeError> {
+        self.evaluate_for_host(|runner| runner.call_with_values(name, arguments.to_vec()))
+    }
+
+    /// Keeps the lines printed along the way apart from the story's own
+    fn evaluate_for_host(
+        &mut self,
+        evaluate: impl FnOnce(&mut Self) -> Result<Value, RuntimeError>,
+    ) -> Result<Evaluated, RuntimeError> {
+        let printed_before = self.state.flow.output.len();
+        let value = evaluate(self);
+        let output: Vec<String> = self
+            .state
+            .flow
+            .output
+            .drain(printed_before..)
+            .map(|line| line.text)
+            .collect();
+        self.notify_observers();
+        Ok(Evaluated {
+            value: value?,
+            output: output.join("\n"),
+        })
+    }
+
+    /// The value of a global variable
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.state.globals.get(name)
+    }
+
+    /// Sets a global variable, which must have been declared with `VAR`.
+    /// Its observers are told straight away.
+    pub fn set_variable(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        self.set_global(name, value)?;
+        self.notify_observers();
+        Ok(())
+    }
+
+    /// Calls `observer` with the global's name, old value and new value whenever
+    /// it's assigned. Assignments are batched up until the end of each `continue_line`,
+    /// where the old value is the one from before the first of them.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner, Value};
+    ///
+    /// let story = parse_story("VAR health = 3\n~ health -= 1\n~ health -= 1\nOuch.\n");
+    /// let mut changes = vec![];
+    /// let mut runner = StoryRunner::new(&story);
+    /// runner
+    ///     .observe_variable("health", |_, old, new| changes.push((old.clone(), new.clone())))
+    ///     .unwrap();
+    /// runner.continue_line().unwrap();
+    /// drop(runner);
+    /// assert_eq!(changes, vec![(Value::Int(3), Value::Int(1))]);
+    /// ```
+    pub fn observe_variable(
+        &mut self,
+        name: &str,
+        observer: impl FnMut(&str, &Value, &Value) + 'a,
+    ) -> Result<(), RuntimeError> {
+        if !self.state.globals.contains_key(name) {
+            return Err(RuntimeError::UnknownVariable(name.to_string()));
+        }
+        self.observers
+            .entry(name.to_string())
+            .or_default()
+            .push(Box::new(observer));
+        Ok(())
+    }
+
+    fn set_global(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        let slot = self
+            .state
+            .globals
+            .get_mut(name)
+            .ok_or_else(|| RuntimeError::UnknownVariable(name.to_string()))?;
+        let old = std::mem::replace(slot, value);
+        self.changed.entry(name.to_string()).or_insert(old);
+        Ok(())
+    }
+
+    pub(crate) fn notify_observers(&mut self) {
+        for (name, old) in std::mem::take(&mut self.changed) {
+            let new = &self.state.globals[&name];
+            for observer in self.observers.get_mut(&name).into_iter().flatten() {
+                observer(&name, &old, new);
+            }
+        }
+    }
+
+    /// Goes to a whole other state, like a saved one, and tells observers about
+    /// the globals that are different there
+    pub(crate) fn replace_state(&mut self, state: State) {
+        let previous = std::mem::replace(&mut self.state, state);
+        for (name, value) in previous.globals {
+            if self.state.globals.get(&name) != Some(&value) {
+                self.changed.entry(name).or_insert(value);
+            }
+        }
+        self.tag_events.clear();
+        self.notify_observers();
+    }
+
+    /// Lets the game provide an ink function, like one declared with `EXTERNAL`.
+    /// A binding is used in place of an ink function with the same name, which is
+    /// otherwise there as a fallback.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner};
+    ///
+    /// let story = parse_story("EXTERNAL has_item(name)\n{has_item(\"key\"): Unlocked.|Locked.}\n");
+    /// let mut runner = StoryRunner::new(&story);
+    /// runner.bind_external("has_item", |name: String| name == "key");
+    /// assert_eq!(runner.continue_line().unwrap().text(), "Unlocked.");
+    /// ```
+    pub fn bind_external<Args, F: ExternalFunction<'a, Args>>(&mut self, name: &str, function: F) {
+        self.externals
+            .insert(name.to_string(), function.into_boxed());
+    }
+
+    /// Seeds `RANDOM()` and shuffles, which are otherwise seeded from the clock.
+    /// The same seed and the same choices always play out the same way.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.state.random = Random::new(seed);
+    }
+
+    /// How many times the knot (or stitch, like `paris.downtown`) has been visited,
+    /// or `None` if there's no such knot
+    pub fn visit_count(&self, path: &str) -> Option<u32> {
+        self.story.knot(path)?;
+        Some(self.state.visits.get(path).copied().unwrap_or(0))
+    }
+
+    /// How many choices have been made so far, which is what `TURNS()` gives
+    pub fn turn_index(&self) -> u32 {
+        self.state.turn_index
+    }
+
+    /// How many turns ago the knot was last visited (0 is this turn),
+    /// or `None` if it hasn't been visited or there's no such knot
+    pub fn turns_since(&self, path: &str) -> Option<u32> {
+        self.state
+            .visited_on_turn
+            .get(path)
+            .and_then(|turn| self.state.turn_index.checked_sub(*turn))
+    }
+
+    fn knot(&self, index: usize) -> &'a Knot {
+        &self.story.knots[index]
+    }
+
+    /// Whether the story has a knot, choice and step for the position to be at
+    pub(crate) fn is_valid(&self, position: Position) -> bool {
+        let knot = match self.story.knots.get(position.knot) {
+            Some(knot) => knot,
+            None => return false,
+        };
+        if matches!(position.choice, Some(choice) if choice >= knot.choices().len()) {
+            return false;
+        }
+        // the step just past the end is where the ending is
+        position.step == 0
+            || !matches!(
+                self.step(Position {
+                    step: position.step - 1,
+                    ..position
+                }),
+                Step::End
+            )
+    }
+
+    fn step(&self, position: Position) -> Step<'a> {
+        let knot = self.knot(position.knot);
+        let (dialog_lines, statements, step) = match position.choice {
+            Some(choice) => {
+                let choice = &knot.choices()[choice];
+                if position.step == 0 {
+                    return Step::ChoiceText;
+                }
+                (&choice.dialog_lines, &choice.statements, position.step - 1)
+            }
+            None => (&knot.dialog_lines, &knot.statements, position.step),
+        };
+
+        // statements come just before the line they were written before
+        let mut statements = statements.iter().peekable();
+        let mut steps = 0;
+        for (index, line) in dialog_lines.iter().enumerate() {
+            while let Some(statement) = statements.next_if(|s| s.before_line <= index) {
+                if steps == step {
+                    return Step::Statement(statement);
+                }
+                steps += 1;
+            }
+            if steps == step {
+                return Step::Line(line);
+            }
+            steps += 1;
+        }
+        statements
+            .nth(step - steps)
+            .map_or(Step::End, Step::Statement)
+    }
+
+    fn advance(&mut self) {
+        if let Some(position) = &mut self.state.flow.position {
+            position.step += 1;
+        }
+    }
+
+    /// Follows endings and diverts until there's a line or statement to run next.
+    /// Statements wait for `continue_line`, so that the host can bind externals and
+    /// observe variables before anything runs.
+    fn follow_endings(&mut self) -> Result<(), RuntimeError> {
+        while let Some(position) = self.state.flow.position {
+            match self.step(position) {
+                Step::End => self.follow_ending(position)?,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs statements and follows endings and diverts until there's a line to give
+    /// out next (or lines printed by a function), so that after a line,
+    /// `can_continue` is only true when there's another one to come
+    fn settle(&mut self) -> Result<(), RuntimeError> {
+        while let Some(position) = self.state.flow.position {
+            if !self.state.flow.output.is_empty() {
+                break;
+            }
+            match self.step(position) {
+                Step::Statement(statement) => {
+                    self.advance();
+                    self.execute(&statement.kind)?;
+                }
+                Step::End => self.follow_ending(position)?,
+                Step::ChoiceText | Step::Line(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn follow_ending(&mut self, position: Position) -> Result<(), RuntimeError> {
+        let knot = self.knot(position.knot);
+        if let Some(choice) = position.choice {
+            return self.divert(&knot.choices()[choice].divert);
+        }
+
+        match &knot.ending {
+            KnotEnding::CHOICES(choices) => {
+                self.state.flow.position = None;
+                self.state.flow.choices.clear();
+                // one at a time, for `CHOICE_COUNT()` in the choices' texts
+                for (index, choice) in choices.iter().enumerate() {
+                    let site = self.site(Position {
+                        choice: Some(index),
+                        ..position
+                    });
+                    let text = self.render(&choice.text, &site)?;
+                    self.state.flow.choices.push(PresentedChoice {
+                        text,
+                        knot: position.knot,
+                        choice: index,
+                    });
+                }
+                Ok(())
+            }
+            KnotEnding::DIVERT(divert) => self.divert(divert),
+            KnotEnding::IMPLICIT => {
+                self.state.flow.position = None;
+                Ok(())
+            }
+        }
+    }
+
+    fn divert(&mut self, divert: &Divert) -> Result<(), RuntimeError> {
+        match divert {
+            Divert::Knot(path) => self.divert_to(path),
+            Divert::Variable(name) => match self.variable_value(name)? {
+                Value::DivertTarget(path) => self.divert_to(&path),
+                other => Err(RuntimeError::InvalidOperation(format!("-> {}", other))),
+            },
+            Divert::End | Divert::Done => {
+                self.state.flow.position = None;
+                Ok(())
+            }
+            // there's no tunnel stack to return along
+            Divert::TunnelReturn => Err(RuntimeError::InvalidOperation(
+                "->-> without a tunnel".to_string(),
+            )),
+        }
+    }
+
+    fn divert_to(&mut self, path: &str) -> Result<(), RuntimeError> {
+        let index = self.knot_index(path)?;
+        self.enter_knot(index);
+        Ok(())
+    }
+
+    /// The first knot with this title, like a divert goes to
+    pub(crate) fn knot_index(&self, path: &str) -> Result<usize, RuntimeError> {
+        self.story
+            .knots
+            .iter()
+            .position(|knot| knot.title == path)
+            .ok_or_else(|| RuntimeError::UnknownKnot(path.to_string()))
+    }
+
+    fn enter_knot(&mut self, index: usize) {
+        self.state.flow.unentered = false;
+        self.tag_events.push(TagEvent::EnteredKnot(index));
+        let title = &self.knot(index).title;
+        *self.state.visits.entry(title.clone()).or_insert(0) += 1;
+        self.state
+            .visited_on_turn
+            .insert(title.clone(), self.state.turn_index);
+        self.state.flow.temps.clear();
+        self.state.flow.position = Some(Position {
+            knot: index,
+            choice: None,
+            step: 0,
+        });
+    }
+
+    fn execute(&mut self, statement: &StatementKind) -> Result<(), RuntimeError> {
+        match statement {
+            StatementKind::TempDeclaration(name, value) => {
+                let value = self.evaluate(value)?;
+                self.state.flow.temps.insert(name.clone(), value);
+            }
+            StatementKind::Assignment(name, value) => {
+                let value = self.evaluate(value)?;
+                self.assign(name, value)?;
+            }
+            StatementKind::AddAssignment(name, value)
+            | StatementKind::SubtractAssignment(name, value) => {
+                let operator = match statement {
+                    StatementKind::AddAssignment(..) => crate::BinaryOperator::Add,
+                    _ => crate::BinaryOperator::Subtract,
+                };
+                let value =
+                    Value::binary(self.variable_value(name)?, operator, self.evaluate(value)?)?;
+                self.assign(name, value)?;
+            }
+            StatementKind::Expression(expression) => {
+                self.evaluate(expression)?;
+            }
+            StatementKind::Return(_) => {
+                return Err(RuntimeError::InvalidOperation(
+                    "~ return outside of a function".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        if let Some(slot) = self.state.flow.temps.get_mut(name) {
+            *slot = value;
+            Ok(())
+        } else {
+            self.set_global(name, value)
+        }
+    }
+
+    /// A temp or global, or else a knot's visit count
+    fn variable_value(&self, name: &str) -> Result<Value, RuntimeError> {
+        self.state
+            .flow
+            .temps
+            .get(name)
+            .or_else(|| self.state.globals.get(name))
+            .cloned()
+            .or_else(|| self.visit_count(name).map(|count| Value::Int(count as i32)))
+            .ok_or_else(|| RuntimeError::UnknownVariable(name.to_string()))
+    }
+
+    fn call(&mut self, name: &str, arguments: &[Expression]) -> Result<Value, RuntimeError> {
+        let mut values = vec![];
+        for argument in arguments {
+            values.push(self.evaluate(argument)?);
+        }
+        self.call_with_values(name, values)
+    }
+
+    /// A builtin, a function the game has bound, or else an ink function
+    fn call_with_values(&mut self, name: &str, values: Vec<Value>) -> Result<Value, RuntimeError> {
+        let count = |count: Option<u32>| Value::Int(count.map_or(-1, |count| count as i32));
+        let invalid = || {
+            let values: Vec<_> = values.iter().map(|value| format!("{:?}", value)).collect();
+            RuntimeError::InvalidOperation(format!("{}({})", name, values.join(", ")))
+        };
+
+        Ok(match (name, values.as_slice()) {
+            ("TURNS", []) => Value::Int(self.state.turn_index as i32),
+            ("CHOICE_COUNT", []) => Value::Int(self.state.flow.choices.len() as i32),
+            ("TURNS_SINCE", [Value::DivertTarget(path)]) => {
+                self.visit_count(path)
+                    .ok_or_else(|| RuntimeError::UnknownKnot(path.clone()))?;
+                count(self.turns_since(path))
+            }
+            ("READ_COUNT", [Value::DivertTarget(path)]) => Value::Int(
+                self.visit_count(path)
+                    .ok_or_else(|| RuntimeError::UnknownKnot(path.clone()))? as i32,
+            ),
+            ("RANDOM", [min, max]) => match (min.as_int(), max.as_int()) {
+                (Some(min), Some(max)) if min <= max => {
+                    Value::Int(self.state.random.between(min, max))
+                }
+                _ => return Err(invalid()),
+            },
+            ("SEED_RANDOM", [seed]) => match seed.as_int() {
+                Some(seed) => {
+                    self.state.random = Random::new(seed as u64);
+                    ().into()
+                }
+                None => return Err(invalid()),
+            },
+            (
+                "TURNS" | "CHOICE_COUNT" | "TURNS_SINCE" | "READ_COUNT" | "RANDOM" | "SEED_RANDOM",
+                _,
+            ) => return Err(invalid()),
+            _ => {
+                if let Some(external) = self.externals.get_mut(name) {
+                    return external(values).map_err(|reason| {
+                        RuntimeError::InvalidOperation(format!("{}(): {}", name, reason))
+                    });
+                }
+                let index = self
+                    .story
+                    .knots
+                    .iter()
+                    .position(|knot| knot.is_function && knot.title == name)
+                    .ok_or_else(|| RuntimeError::UnknownFunction(name.to_string()))?;
+                return self.call_ink_function(index, values);
+            }
+        })
+    }
+
+    /// Runs an ink function until it returns or runs out, with its arguments as its temps.
+    /// Lines it prints go to `state.flow.output`.
+    fn call_ink_function(
+        &mut self,
+        index: usize,
+        arguments: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        let function = self.knot(index);
+        if arguments.len() != function.parameters.len() {
+            return Err(RuntimeError::InvalidOperation(format!(
+                "{}() with {} arguments, not {}",
+                function.title,
+                arguments.len(),
+                function.parameters.len()
+            )));
+        }
+
+        let temps = function.parameters.iter().cloned().zip(arguments).collect();
+        let caller_temps = std::mem::replace(&mut self.state.flow.temps, temps);
+        let result = self.run_function(index);
+        self.state.flow.temps = caller_temps;
+        result
+    }
+
+    fn run_function(&mut self, index: usize) -> Result<Value, RuntimeError> {
+        for step in 0.. {
+            let position = Position {
+                knot: index,
+                choice: None,
+                step,
+            };
+            match self.step(position) {
+                Step::Line(line) => {
+                    let line = self.render_line(line, &self.site(position))?;
+                    self.state.flow.output.push(line);
+                }
+                Step::Statement(statement) => match &statement.kind {
+                    StatementKind::Return(Some(value)) => return self.evaluate(value),
+                    StatementKind::Return(None) => break,
+                    kind => self.execute(kind)?,
+                },
+                Step::ChoiceText | Step::End => break,
+            }
+        }
+        Ok(().into())
+    }
+
+    pub(crate) fn evaluate(&mut self, expression: &Expression) -> Result<Value, RuntimeError> {
+        Ok(match expression {
+            Expression::Int(n) => Value::Int(*n),
+            Expression::Float(x) => Value::Float(*x),
+            Expression::Bool(b) => Value::Bool(*b),
+            Expression::Str(s) => Value::Str(s.clone()),
+            Expression::DivertTarget(target) => Value::DivertTarget(target.clone()),
+            Expression::Variable(name) => self.variable_value(name)?,
+            Expression::Unary(operator, operand) => {
+                let operand = self.evaluate(operand)?;
+                Value::unary(*operator, operand)?
+            }
+            Expression::Binary(left, operator, right) => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+                Value::binary(left, *operator, right)?
+            }
+            Expression::Call(name, arguments) => self.call(name, arguments)?,
+        })
+    }
+
+    /// Where a step is, like `paris.c-1.2`, to keep count of its alternatives
+    fn site(&self, position: Position) -> String {
+        let title = &self.knot(position.knot).title;
+        match position.choice {
+            Some(choice) => format!("{}.c-{}.{}", title, choice, position.step),
+            None => format!("{}.{}", title, position.step),
+        }
+    }
+
+    fn render_line(&mut self, line: &DialogLine, site: &str) -> Result<Line, RuntimeError> {
+        Ok(Line {
+            text: self.render(&line.text, site)?,
+            tags: line.tags.clone(),
+            speaker: line.speaker.clone(),
+        })
+    }
+
+    /// Works out the `{}`s in a piece of text: `{x}` prints x, `{x: a|b}` picks a or b,
+    /// and `{a|b}` (or `{&a|b}`, `{!a|b}`, `{~a|b}`) is an alternative.
+    /// Anything else in braces is printed as it is.
+    ///
+    /// `site` is where the text is, so that each alternative keeps its own count.
+    fn render(&mut self, text: &str, site: &str) -> Result<String, RuntimeError> {
+        let mut rendered = String::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            rendered.push_str(&rest[..open]);
+            let close = match matching_brace(&rest[open..]) {
+                Some(close) => open + close,
+                None => {
+                    rendered.push_str(&rest[open..]);
+                    return Ok(rendered.trim().to_string());
+                }
+            };
+            let inside = &rest[open + 1..close];
+            let site = format!("{}:{}", site, text.len() - rest.len() + open);
+            rendered.push_str(&self.render_braces(inside, &site)?);
+            rest = &rest[close + 1..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered.trim().to_string())
+    }
+
+    fn render_braces(&mut self, inside: &str, site: &str) -> Result<String, RuntimeError> {
+        if let Some(kind) = inside.chars().next().filter(|c| "&!~".contains(*c)) {
+            let parts = split_top_level(&inside[1..], '|');
+            return self.render_alternative(kind, &parts, site);
+        }
+
+        let parts = split_top_level(inside, '|');
+        if let Some((condition, then)) = split_once_top_level(parts[0], ':') {
+            if let Some(condition) = parse_inline_expression(condition) {
+                let branch = if self.evaluate(&condition)?.is_truthy() {
+                    then
+                } else {
+                    parts.get(1).copied().unwrap_or("")
+                };
+                return self.render(branch, site);
+            }
+        } else if parts.len() == 1 {
+            if let Some(expression) = parse_inline_expression(inside) {
+                // lines printed by functions go in with the value
+                let printed_before = self.state.flow.output.len();
+                let value = self.evaluate(&expression)?;
+                let mut printed: Vec<String> = self
+                    .state
+                    .flow
+                    .output
+                    .drain(printed_before..)
+                    .map(|line| line.text)
+                    .collect();
+                printed.push(value.to_string());
+                return Ok(printed.join(" ").trim().to_string());
+            }
+        } else {
+            return self.render_alternative('|', &parts, site);
+        }
+        Ok(format!("{{{}}}", inside))
+    }
+
+    /// `kind` is `|` for a sequence, which sticks on its last part,
+    /// `&` for a cycle, `!` for once-only, and `~` for a shuffle
+    fn render_alternative(
+        &mut self,
+        kind: char,
+        parts: &[&str],
+        site: &str,
+    ) -> Result<String, RuntimeError> {
+        let shown = self.state.alternatives.entry(site.to_string()).or_insert(0);
+        let count = *shown as usize;
+        *shown += 1;
+
+        let last = parts.len() - 1;
+        let index = match kind {
+            '&' => Some(count % parts.len()),
+            '!' => Some(count).filter(|&index| index <= last),
+            '~' => Some(self.state.random.shuffled(
+                site,
+                count / parts.len(),
+                count % parts.len(),
+                parts.len(),
+            )),
+            _ => Some(count.min(last)),
+        };
+        match index {
+            Some(index) => self.render(parts[index], &format!("{}-{}", site, index)),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+/// The index of the `}` that closes the `{` at the start of `text`
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits on `separator`, except inside nested braces
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ if c == separator && depth == 0 => {
+                parts.push(&text[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn split_once_top_level(text: &str, separator: char) -> Option<(&str, &str)> {
+    let parts = split_top_level(text, separator);
+    if parts.len() < 2 {
+        return None;
+    }
+    let first = parts[0];
+    Some((first, &text[first.len() + separator.len_utf8()..]))
+}
@@ -0,0 +1,469 @@
+use crate::random::Random;
+use crate::runtime::{Flow, Position, PresentedChoice, State};
+use crate::{Line, RuntimeError, StoryRunner, Tag, Value, DEFAULT_FLOW};
+use serde_json::{json, Map, Value as Json};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The version of the JSON that `StoryRunner::save_state` writes.
+///
+/// ```json
+/// {
+///   "version": 2,
+///   "globals": { "health": 3, "destination": { "^->": "paris" } },
+///   "visits": { "INTRO": 1 },
+///   "visited_on_turn": { "INTRO": 0 },
+///   "turn_index": 0,
+///   "seed": 42,
+///   "random": 12345,
+///   "alternatives": { "INTRO.2:10": 1 },
+///   "flow": "DEFAULT_FLOW",
+///   "flows": {
+///     "DEFAULT_FLOW": {
+///       "temps": { "days": 80 },
+///       "position": { "knot": "INTRO", "choice": 0, "step": 2 },
+///       "unentered": false,
+///       "choices": [{ "knot": "INTRO", "choice": 1, "text": "Rest" }],
+///       "choice_text": null,
+///       "output": [{ "text": "Ding!", "tags": ["sound: bell"], "speaker": null }],
+///       "error": { "kind": "UnknownKnot", "detail": "nowhere" }
+///     }
+///   }
+/// }
+/// ```
+///
+/// Knots are saved by title, so a save still fits a story that has had knots added,
+/// or moved around. `position`, `choice_text` and `error` are `null` when there's
+/// nothing there. `unentered` is true for a flow that hasn't gone into the knot
+/// it's at yet. `random` is where the random numbers from `seed` are up to, and
+/// `flow` is the flow that was running.
+///
+/// Version 1 saves, from before there were flows, are still read: they had just
+/// the one flow, with its fields alongside the others, and no `speaker`s,
+/// `unentered` or `seed`.
+pub const STATE_VERSION: u32 = 2;
+
+/// A saved state that couldn't be restored.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RestoreError {
+    /// not JSON, or not shaped like a saved state
+    Malformed(String),
+    UnsupportedVersion(u64),
+    /// the state was saved with a story that has since changed, and doesn't fit this one
+    IncompatibleStory(String),
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::Malformed(reason) => write!(f, "not a saved state: {}", reason),
+            RestoreError::UnsupportedVersion(version) => write!(
+                f,
+                "saved state version {} isn't supported (only up to {})",
+                version, STATE_VERSION
+            ),
+            RestoreError::IncompatibleStory(reason) => {
+                write!(f, "the story has changed since this was saved: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+impl StoryRunner<'_> {
+    /// Everything needed to carry on from here later, as JSON (see `STATE_VERSION`)
+    pub fn save_state(&self) -> String {
+        let state = &self.state;
+        let mut flows = Map::new();
+        flows.insert(state.flow_name.clone(), self.flow_json(&state.flow));
+        for (name, flow) in &state.flows {
+            flows.insert(name.clone(), self.flow_json(flow));
+        }
+
+        json!({
+            "version": STATE_VERSION,
+            "globals": values_json(&state.globals),
+            "visits": state.visits,
+            "visited_on_turn": state.visited_on_turn,
+            "turn_index": state.turn_index,
+            "seed": state.random.seed(),
+            "random": state.random.state(),
+            "alternatives": state.alternatives,
+            "flow": state.flow_name,
+            "flows": flows,
+        })
+        .to_string()
+    }
+
+    fn flow_json(&self, flow: &Flow) -> Json {
+        let title = |knot: usize| self.story.knots[knot].title.clone();
+        json!({
+            "temps": values_json(&flow.temps),
+            "position": flow.position.map_or(Json::Null, |position| json!({
+                "knot": title(position.knot),
+                "choice": position.choice,
+                "step": position.step,
+            })),
+            "unentered": flow.unentered,
+            "choices": flow.choices.iter().map(|choice| json!({
+                "knot": title(choice.knot),
+                "choice": choice.choice,
+                "text": choice.text,
+            })).collect::<Vec<_>>(),
+            "choice_text": flow.choice_text,
+            "output": flow.output.iter().map(|line| json!({
+                "text": line.text(),
+                "tags": line.tags().iter().map(Tag::raw).collect::<Vec<_>>(),
+                "speaker": line.speaker(),
+            })).collect::<Vec<_>>(),
+            "error": flow.pending_error.as_ref().map_or(Json::Null, error_json),
+        })
+    }
+
+    /// Carries on from a state that `save_state` gave, which should have been saved
+    /// with this story. Nothing changes if it can't be restored; if it can, the
+    /// history kept for `rewind` is forgotten, and observers hear about the globals
+    /// that are different now.
+    ///
+    /// Variables the story has added since start with their initial values, worked
+    /// out from the restored globals, and visits to knots that have since been
+    /// removed are forgotten. A save doesn't fit if one of those initial values
+    /// can't be worked out, though any externals it called have still run.
+    pub fn restore_state(&mut self, saved: &str) -> Result<(), RestoreError> {
+        let json: Json = serde_json::from_str(saved)
+            .map_err(|error| RestoreError::Malformed(error.to_string()))?;
+
+        let version = field(&json, "version")?
+            .as_u64()
+            .ok_or_else(|| malformed("version"))?;
+        if version == 0 || version > u64::from(STATE_VERSION) {
+            return Err(RestoreError::UnsupportedVersion(version));
+        }
+
+        let globals = values(field(&json, "globals")?)?;
+        if let Some(name) = globals
+            .keys()
+            .find(|name| !self.story.variables.contains_key(*name))
+        {
+            return Err(RestoreError::IncompatibleStory(format!(
+                "there's no variable called {}",
+                name
+            )));
+        }
+
+        let mut flows = BTreeMap::new();
+        let flow_name = if version == 1 {
+            flows.insert(
+                DEFAULT_FLOW.to_string(),
+                self.flow_from_json(&json, version)?,
+            );
+            DEFAULT_FLOW.to_string()
+        } else {
+            for (name, flow) in object(field(&json, "flows")?)? {
+                flows.insert(name.clone(), self.flow_from_json(flow, version)?);
+            }
+            string(field(&json, "flow")?)?.to_string()
+        };
+        let flow = flows.remove(&flow_name).ok_or_else(|| malformed("flow"))?;
+        if flow_name != DEFAULT_FLOW && !flows.contains_key(DEFAULT_FLOW) {
+            return Err(malformed("flows"));
+        }
+
+        let mut alternatives = BTreeMap::new();
+        for (site, shown) in object(field(&json, "alternatives")?)? {
+            alternatives.insert(site.clone(), count(shown)?);
+        }
+
+        let turn_index = count(field(&json, "turn_index")?)?;
+        let visited_on_turn = self.counts(field(&json, "visited_on_turn")?)?;
+        if visited_on_turn.values().any(|turn| *turn > turn_index) {
+            return Err(malformed("visited_on_turn"));
+        }
+
+        let visits = self.counts(field(&json, "visits")?)?;
+        let random = field(&json, "random")?
+            .as_u64()
+            .ok_or_else(|| malformed("random"))?;
+        // version 1 shuffled with the random numbers themselves
+        let seed = match version {
+            1 => random,
+            _ => field(&json, "seed")?
+                .as_u64()
+                .ok_or_else(|| malformed("seed"))?,
+        };
+
+        let mut restored = State {
+            globals,
+            visits,
+            visited_on_turn,
+            turn_index,
+            random: Random::resume(seed, random),
+            alternatives,
+            flow_name,
+            flow,
+            flows,
+        };
+
+        // Initial values are worked out in the restored state, once everything else is
+        // known to fit, since they can use its globals, call functions and roll random
+        // numbers. Assignments along the way are part of restoring, not changes.
+        let previous = std::mem::replace(&mut self.state, restored);
+        let changed = std::mem::take(&mut self.changed);
+        let initialized = self.initialize_new_globals();
+        self.changed = changed;
+        restored = std::mem::replace(&mut self.state, previous);
+        initialized?;
+
+        self.replace_state(restored);
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Gives the variables that weren't saved their initial values. One initial
+    /// value can use another, so they're tried until none of those left can be.
+    fn initialize_new_globals(&mut self) -> Result<(), RestoreError> {
+        let story = self.story;
+        let mut uninitialized: Vec<_> = story
+            .variables
+            .iter()
+            .filter(|(name, _)| !self.state.globals.contains_key(*name))
+            .collect();
+
+        while !uninitialized.is_empty() {
+            let mut failure = None;
+            let before = uninitialized.len();
+            uninitialized.retain(|&(name, initial)| match self.evaluate(initial) {
+                Ok(value) => {
+                    self.state.globals.insert(name.clone(), value);
+                    false
+                }
+                Err(error) => {
+                    failure = Some((name, error));
+                    true
+                }
+            });
+            if let Some((name, error)) = failure.filter(|_| uninitialized.len() == before) {
+                return Err(RestoreError::IncompatibleStory(format!(
+                    "{} can't start with its initial value: {}",
+                    name, error
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn flow_from_json(&self, json: &Json, version: u64) -> Result<Flow, RestoreError> {
+        let position = match field(json, "position")? {
+            Json::Null => None,
+            position => {
+                let position = Position {
+                    knot: self.saved_knot(field(position, "knot")?)?,
+                    choice: match field(position, "choice")? {
+                        Json::Null => None,
+                        choice => Some(index(choice)?),
+                    },
+                    step: index(field(position, "step")?)?,
+                };
+                if !self.is_valid(position) {
+                    return Err(RestoreError::IncompatibleStory(format!(
+                        "{} has changed",
+                        self.story.knots[position.knot].title
+                    )));
+                }
+                Some(position)
+            }
+        };
+
+        let mut choices = vec![];
+        for choice in array(field(json, "choices")?)? {
+            let choice = PresentedChoice {
+                text: string(field(choice, "text")?)?.to_string(),
+                knot: self.saved_knot(field(choice, "knot")?)?,
+                choice: index(field(choice, "choice")?)?,
+            };
+            if choice.choice >= self.story.knots[choice.knot].choices().len() {
+                return Err(RestoreError::IncompatibleStory(format!(
+                    "{} has fewer choices",
+                    self.story.knots[choice.knot].title
+                )));
+            }
+            choices.push(choice);
+        }
+
+        let choice_text = match field(json, "choice_text")? {
+            Json::Null => None,
+            text => Some(string(text)?.to_string()),
+        };
+
+        let mut output = vec![];
+        for line in array(field(json, "output")?)? {
+            let mut tags = vec![];
+            for tag in array(field(line, "tags")?)? {
+                tags.push(Tag::from(string(tag)?));
+            }
+            // version 1 didn't keep speakers apart from their lines
+            let speaker = match version {
+                1 => None,
+                _ => match field(line, "speaker")? {
+                    Json::Null => None,
+                    speaker => Some(string(speaker)?.to_string()),
+                },
+            };
+            output.push(Line::new(string(field(line, "text")?)?, tags, speaker));
+        }
+
+        let pending_error = match field(json, "error")? {
+            Json::Null => None,
+            error => Some(runtime_error(error)?),
+        };
+
+        // there was only the one flow in version 1, and it had always gone in
+        let unentered = match version {
+            1 => false,
+            _ => field(json, "unentered")?
+                .as_bool()
+                .ok_or_else(|| malformed("unentered"))?,
+        };
+
+        Ok(Flow {
+            temps: values(field(json, "temps")?)?,
+            position,
+            unentered,
+            choices,
+            pending_error,
+            choice_text,
+            output,
+        })
+    }
+
+    fn saved_knot(&self, title: &Json) -> Result<usize, RestoreError> {
+        let title = string(title)?;
+        self.knot_index(title).map_err(|_| {
+            RestoreError::IncompatibleStory(format!("there's no knot called {}", title))
+        })
+    }
+
+    /// Counts by knot title, leaving out knots that aren't in the story
+    fn counts(&self, json: &Json) -> Result<BTreeMap<String, u32>, RestoreError> {
+        let mut counts = BTreeMap::new();
+        for (title, value) in object(json)? {
+            let value = count(value)?;
+            if self.story.knot(title).is_some() {
+                counts.insert(title.clone(), value);
+            }
+        }
+        Ok(counts)
+    }
+}
+
+fn values_json(values: &BTreeMap<String, Value>) -> Json {
+    values
+        .iter()
+        .map(|(name, value)| (name.clone(), value_json(value)))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// Like ink's JSON, except that strings aren't prefixed with `^`
+fn value_json(value: &Value) -> Json {
+    match value {
+        Value::Int(n) => json!(n),
+        Value::Float(x) => json!(x),
+        Value::Bool(b) => json!(b),
+        Value::Str(s) => json!(s),
+        Value::DivertTarget(target) => json!({ "^->": target }),
+    }
+}
+
+fn values(json: &Json) -> Result<BTreeMap<String, Value>, RestoreError> {
+    let mut values = BTreeMap::new();
+    for (name, value) in object(json)? {
+        values.insert(name.clone(), value_from_json(value)?);
+    }
+    Ok(values)
+}
+
+fn value_from_json(json: &Json) -> Result<Value, RestoreError> {
+    match json {
+        Json::Bool(b) => Ok(Value::Bool(*b)),
+        Json::String(s) => Ok(Value::Str(s.clone())),
+        Json::Number(n) if n.is_f64() => Ok(Value::Float(n.as_f64().unwrap_or_default())),
+        Json::Number(n) => n
+            .as_i64()
+            .and_then(|n| i32::try_from(n).ok())
+            .map(Value::Int)
+            .ok_or_else(|| malformed("value")),
+        Json::Object(object) => match object.get("^->") {
+            Some(Json::String(target)) => Ok(Value::DivertTarget(target.clone())),
+            _ => Err(malformed("value")),
+        },
+        _ => Err(malformed("value")),
+    }
+}
+
+fn error_json(error: &RuntimeError) -> Json {
+    let (kind, detail) = match error {
+        RuntimeError::CannotContinue => ("CannotContinue", Json::Null),
+        RuntimeError::NoSuchChoice(index) => ("NoSuchChoice", json!(index)),
+        RuntimeError::UnknownKnot(title) => ("UnknownKnot", json!(title)),
+        RuntimeError::UnknownVariable(name) => ("UnknownVariable", json!(name)),
+        RuntimeError::UnknownFunction(name) => ("UnknownFunction", json!(name)),
+        RuntimeError::InvalidExpression(source) => ("InvalidExpression", json!(source)),
+        RuntimeError::CannotRewind(choices) => ("CannotRewind", json!(choices)),
+        RuntimeError::UnknownFlow(name) => ("UnknownFlow", json!(name)),
+        RuntimeError::InvalidOperation(operation) => ("InvalidOperation", json!(operation)),
+    };
+    json!({ "kind": kind, "detail": detail })
+}
+
+fn runtime_error(json: &Json) -> Result<RuntimeError, RestoreError> {
+    let detail = field(json, "detail")?;
+    let text = || string(detail).map(str::to_string);
+    match string(field(json, "kind")?)? {
+        "CannotContinue" => Ok(RuntimeError::CannotContinue),
+        "NoSuchChoice" => Ok(RuntimeError::NoSuchChoice(index(detail)?)),
+        "UnknownKnot" => Ok(RuntimeError::UnknownKnot(text()?)),
+        "UnknownVariable" => Ok(RuntimeError::UnknownVariable(text()?)),
+        "UnknownFunction" => Ok(RuntimeError::UnknownFunction(text()?)),
+        "InvalidExpression" => Ok(RuntimeError::InvalidExpression(text()?)),
+        "CannotRewind" => Ok(RuntimeError::CannotRewind(index(detail)?)),
+        "UnknownFlow" => Ok(RuntimeError::UnknownFlow(text()?)),
+        "InvalidOperation" => Ok(RuntimeError::InvalidOperation(text()?)),
+        _ => Err(malformed("error")),
+    }
+}
+
+fn malformed(what: &str) -> RestoreError {
+    RestoreError::Malformed(format!("bad {}", what))
+}
+
+fn field<'a>(json: &'a Json, name: &str) -> Result<&'a Json, RestoreError> {
+    json.get(name)
+        .ok_or_else(|| RestoreError::Malformed(format!("no {}", name)))
+}
+
+fn object(json: &Json) -> Result<&Map<String, Json>, RestoreError> {
+    json.as_object().ok_or_else(|| malformed("object"))
+}
+
+fn array(json: &Json) -> Result<&Vec<Json>, RestoreError> {
+    json.as_array().ok_or_else(|| malformed("array"))
+}
+
+fn string(json: &Json) -> Result<&str, RestoreError> {
+    json.as_str().ok_or_else(|| malformed("string"))
+}
+
+fn index(json: &Json) -> Result<usize, RestoreError> {
+    json.as_u64()
+        .and_then(|n| usize::try_from(n).ok())
+        .ok_or_else(|| malformed("index"))
+}
+
+fn count(json: &Json) -> Result<u32, RestoreError> {
+    json.as_u64()
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or_else(|| malformed("count"))
+}
@@ -0,0 +1,108 @@
+use crate::{Expression, Knot, Story, Tag};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The version of the shape a `Story` is serialized in. It goes up whenever that shape
+/// changes in a way older readers can't handle.
+///
+/// Version 2, as JSON:
+///
+/// ```json
+/// {
+///   "version": 2,
+///   "global_tags": [{ "raw": "title: Paris", "key": "title", "value": "Paris" }],
+///   "variables": { "health": { "Int": 3 } },
+///   "externals": { "play_sound": ["name"] },
+///   "knots": [
+///     {
+///       "title": "INTRO",
+///       "parameters": [],
+///       "is_function": false,
+///       "tags": [],
+///       "dialog_lines": [
+///         { "text": "Hello.", "tags": [], "speaker": null, "speaker_prefix": null }
+///       ],
+///       "statements": [
+///         { "before_line": 1, "kind": { "SubtractAssignment": ["health", { "Int": 1 }] } }
+///       ],
+///       "ending": { "CHOICES": [
+///         {
+///           "text": "Go",
+///           "dialog_lines": [],
+///           "statements": [],
+///           "divert": { "Knot": "paris" }
+///         }
+///       ] }
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Enums use serde's default representation: `{ "Knot": "paris" }`, `"IMPLICIT"`,
+/// and so on. A divert is an object like that, with its `span` alongside, so the
+/// ones that are just a name are written `{ "End": null }`.
+/// Every node may also have a `span` (`{ "file": 0, "start": 0, "end": 6 }`, in bytes),
+/// which is left out by hand-written stories and defaults to an empty span.
+/// `externals`, and a knot's `parameters` and `is_function`, can be left out too.
+///
+/// Version 2 added externals and functions, and took diverts out of the `node`
+/// field they were wrapped in. Version 1 stories aren't read anymore.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// A serialized story from a version of this crate that wrote it in a different shape
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UnsupportedVersion(pub u32);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "story format version {} isn't supported (expected {})",
+            self.0, FORMAT_VERSION
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// What a `Story` looks like once serialized, with its format version alongside.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct StoryJson {
+    version: u32,
+    #[serde(default)]
+    global_tags: Vec<Tag>,
+    #[serde(default)]
+    variables: BTreeMap<String, Expression>,
+    #[serde(default)]
+    externals: BTreeMap<String, Vec<String>>,
+    knots: Vec<Knot>,
+}
+
+impl From<Story> for StoryJson {
+    fn from(story: Story) -> Self {
+        StoryJson {
+            version: FORMAT_VERSION,
+            global_tags: story.global_tags,
+            variables: story.variables,
+            externals: story.externals,
+            knots: story.knots,
+        }
+    }
+}
+
+impl TryFrom<StoryJson> for Story {
+    type Error = UnsupportedVersion;
+
+    fn try_from(json: StoryJson) -> Result<Self, Self::Error> {
+        if json.version != FORMAT_VERSION {
+            return Err(UnsupportedVersion(json.version));
+        }
+        Ok(Story {
+            global_tags: json.global_tags,
+            variables: json.variables,
+            externals: json.externals,
+            knots: json.knots,
+        })
+    }
+}
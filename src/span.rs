@@ -0,0 +1,183 @@
+use combine::stream::position::{Positioner, RangePositioner};
+use combine::{position, ParseError, Parser, Stream};
+use std::ops::{Deref, DerefMut};
+
+/// Which file a span is in. The main story file is `FileId(0)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileId(pub u32);
+
+/// A point in a story's text, as a byte offset into a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    file: FileId,
+    offset: usize,
+}
+
+impl Location {
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// 1-based, in the text of this location's file
+    pub fn line_column(&self, text: &str) -> (usize, usize) {
+        line_column(text, self.offset)
+    }
+}
+
+/// Where something came from in the story's text, as byte offsets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    file: FileId,
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    pub fn new(start: Location, end: Location) -> Self {
+        Span {
+            file: start.file,
+            start: start.offset,
+            end: end.offset,
+        }
+    }
+
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// From the start of this span to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+
+    /// 1-based line and column of the start of the span, in the text of its file
+    pub fn line_column(&self, text: &str) -> (usize, usize) {
+        line_column(text, self.start)
+    }
+}
+
+/// Turns a byte offset into a 1-based line and column. Columns count chars, not bytes.
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    // an offset inside a char counts as that char
+    let end = (0..=offset.min(text.len()))
+        .rev()
+        .find(|&index| text.is_char_boundary(index))
+        .unwrap_or(0);
+    let before = &text[..end];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Something that doesn't have room for a span of its own, like an enum.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spanned<T> {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    node: T,
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+
+    pub fn node(&self) -> &T {
+        &self.node
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T> DerefMut for Spanned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.node
+    }
+}
+
+impl<T> From<T> for Spanned<T> {
+    fn from(node: T) -> Self {
+        Spanned::new(node, Span::default())
+    }
+}
+
+/// Counts bytes (not chars), so positions can be used to slice the story's text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BytePositioner(Location);
+
+impl Positioner<char> for BytePositioner {
+    type Position = Location;
+    type Checkpoint = Self;
+
+    fn position(&self) -> Location {
+        self.0
+    }
+
+    fn update(&mut self, token: &char) {
+        self.0.offset += token.len_utf8();
+    }
+
+    fn checkpoint(&self) -> Self {
+        *self
+    }
+
+    fn reset(&mut self, checkpoint: Self) {
+        *self = checkpoint;
+    }
+}
+
+impl<'a> RangePositioner<char, &'a str> for BytePositioner {
+    fn update_range(&mut self, range: &&'a str) {
+        self.0.offset += range.len();
+    }
+}
+
+/// The text of a story, which keeps track of how far into it we are.
+pub type StoryStream<'a> = combine::stream::position::Stream<&'a str, BytePositioner>;
+
+/// What the story's parsers, like `dialog_line`, read from
+pub fn story_stream(text: &str, file: FileId) -> StoryStream<'_> {
+    StoryStream::with_positioner(text, BytePositioner(Location { file, offset: 0 }))
+}
+
+/// Runs `parser`, and also gives back the span of what it consumed
+pub(crate) fn spanned<Input, P>(parser: P) -> impl Parser<Input, Output = (P::Output, Span)>
+where
+    Input: Stream<Token = char, Position = Location>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    P: Parser<Input>,
+{
+    (position(), parser, position()).map(|(start, output, end)| (output, Span::new(start, end)))
+}
@@ -0,0 +1,86 @@
+use crate::{DialogLine, Story, VisitMut};
+
+/// How `Fogg: "We leave at 8:25."` is split into a speaker and their line.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpeakerSyntax {
+    /// what comes between the speaker's name and what they say, like `:` or ` - `,
+    /// as plain text rather than a pattern
+    separator: String,
+    /// the global tag listing everyone who can speak, like `# characters: Fogg, Passepartout`
+    characters_tag: String,
+}
+
+impl SpeakerSyntax {
+    pub fn new(separator: &str, characters_tag: &str) -> Self {
+        SpeakerSyntax {
+            separator: separator.to_string(),
+            characters_tag: characters_tag.to_string(),
+        }
+    }
+}
+
+impl Default for SpeakerSyntax {
+    fn default() -> Self {
+        SpeakerSyntax::new(":", "characters")
+    }
+}
+
+impl Story {
+    /// The characters named in the story's characters tag.
+    pub fn characters(&self, syntax: &SpeakerSyntax) -> Vec<String> {
+        self.global_tag(&syntax.characters_tag)
+            .and_then(|tag| tag.value())
+            .map(|names| {
+                names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Moves the speaker's name out of each dialog line that starts with one.
+    /// Only characters from the story's characters tag count, so lines like
+    /// `health: "{health}"` are left alone.
+    pub fn extract_speakers(&mut self, syntax: &SpeakerSyntax) {
+        struct ExtractSpeakers {
+            separator: String,
+            characters: Vec<String>,
+        }
+
+        impl VisitMut for ExtractSpeakers {
+            fn visit_dialog_line_mut(&mut self, line: &mut DialogLine) {
+                line.extract_speaker(&self.separator, &self.characters);
+            }
+        }
+
+        ExtractSpeakers {
+            separator: syntax.separator.clone(),
+            characters: self.characters(syntax),
+        }
+        .visit_story_mut(self);
+    }
+}
+
+impl DialogLine {
+    fn extract_speaker(&mut self, separator: &str, characters: &[String]) {
+        if self.speaker.is_some() {
+            return;
+        }
+
+        let (name, rest) = match self.text.split_once(separator) {
+            Some(split) => split,
+            None => return,
+        };
+
+        if characters.iter().any(|character| character == name.trim()) {
+            let text = rest.trim_start().to_string();
+            let prefix_length = self.text.len() - text.len();
+            self.speaker = Some(name.trim().to_string());
+            self.speaker_prefix = Some(self.text[..prefix_length].to_string());
+            self.text = text;
+        }
+    }
+}
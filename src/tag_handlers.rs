@@ -0,0 +1,96 @@
+use crate::{Line, StoryRunner, Tag};
+
+/// Where a tag that a handler hears about was in the story
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TagSource<'s> {
+    /// on a line that `continue_line` gave out
+    Line(&'s Line),
+    /// at the top of a knot (or stitch) the story went into, by its title
+    Knot(&'s str),
+}
+
+/// Called with each tag that `matches_prefix` the prefix it was added for
+pub(crate) type TagHandler<'a> = Box<dyn FnMut(&Tag, TagSource<'_>) + 'a>;
+
+/// Something with tags that happened while the story ran, waiting for handlers
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum TagEvent {
+    EnteredKnot(usize),
+    /// where the line being given out comes among the knots entered
+    Line,
+}
+
+impl<'a> StoryRunner<'a> {
+    /// Calls `handler` with every tag that starts with `prefix`, like `audio:` or
+    /// `camera`, as a whole word: `camera` is there in `#camera`, `#camera close` and
+    /// `#camera: wide`, but not in `#cameraman`. The handler hears about them as the
+    /// story gets to them: the tags on each line `continue_line` gives out, and those
+    /// at the top of each knot it goes into. Handlers hear about tags in the order
+    /// they come in the story, once `continue_line` is done, and in the order they
+    /// were added when more than one wants the same tag.
+    ///
+    /// ```
+    /// use ink_parser::{parse_story, StoryRunner, TagSource};
+    ///
+    /// let story = parse_story("Boom! #audio: explosion #shake\n");
+    /// let mut sounds = vec![];
+    /// let mut runner = StoryRunner::new(&story);
+    /// runner.on_tag("audio:", |tag, source| {
+    ///     if let TagSource::Line(line) = source {
+    ///         sounds.push((tag.value().unwrap().to_string(), line.text().to_string()));
+    ///     }
+    /// });
+    /// runner.continue_line().unwrap();
+    /// drop(runner);
+    /// assert_eq!(sounds, vec![("explosion".to_string(), "Boom!".to_string())]);
+    /// ```
+    pub fn on_tag(&mut self, prefix: &str, handler: impl FnMut(&Tag, TagSource<'_>) + 'a) {
+        self.tag_handlers
+            .push((prefix.to_string(), Box::new(handler)));
+    }
+
+    /// Tells handlers about the tags since they were last told, with `line` coming
+    /// where it was given out (or last, if it was already waiting)
+    pub(crate) fn handle_tags(&mut self, line: Option<&Line>) {
+        let story = self.story;
+        let mut line = line;
+        let events = std::mem::take(&mut self.tag_events);
+        for event in events.into_iter().chain(std::iter::once(TagEvent::Line)) {
+            match event {
+                TagEvent::EnteredKnot(index) => {
+                    let knot = &story.knots[index];
+                    for tag in knot.tags() {
+                        self.handle_tag(tag, TagSource::Knot(&knot.title));
+                    }
+                }
+                TagEvent::Line => {
+                    if let Some(line) = line.take() {
+                        for tag in line.tags() {
+                            self.handle_tag(tag, TagSource::Line(line));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_tag(&mut self, tag: &Tag, source: TagSource<'_>) {
+        for (prefix, handler) in &mut self.tag_handlers {
+            if matches_prefix(tag.raw(), prefix) {
+                handler(tag, source);
+            }
+        }
+    }
+}
+
+/// Whether `raw` starts with `prefix`, and doesn't carry on with more of the same word
+fn matches_prefix(raw: &str, prefix: &str) -> bool {
+    match raw.strip_prefix(prefix) {
+        Some(rest) => {
+            rest.is_empty()
+                || prefix.ends_with(|c: char| c == ':' || c.is_whitespace())
+                || rest.starts_with(|c: char| c == ':' || c.is_whitespace())
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,107 @@
+use crate::span::Span;
+
+/// How tags are split into a key and a value.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TagSyntax {
+    /// the first of these that's in a tag splits it, where it first comes up
+    separators: Vec<char>,
+}
+
+impl TagSyntax {
+    pub fn new(separators: &[char]) -> Self {
+        TagSyntax {
+            separators: separators.to_vec(),
+        }
+    }
+}
+
+impl Default for TagSyntax {
+    /// `#speaker: Fogg` and `#camera close` both have a key and a value, and
+    /// `#time: 8:25 pm` splits at its `:`, not at its spaces
+    fn default() -> Self {
+        TagSyntax::new(&[':', ' '])
+    }
+}
+
+/// A `#` tag. Tags without a separator, like `#surly`, have no key or value.
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tag {
+    raw: String,
+    key: Option<String>,
+    value: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Span,
+}
+
+impl Tag {
+    pub fn parse(raw: &str, syntax: &TagSyntax) -> Self {
+        let raw = raw.trim();
+        let split = syntax
+            .separators
+            .iter()
+            .find_map(|&separator| raw.find(separator));
+        match split {
+            Some(index) => {
+                let separator_length = raw[index..].chars().next().map_or(0, char::len_utf8);
+                Tag {
+                    raw: raw.to_string(),
+                    key: Some(raw[..index].trim_end().to_string()),
+                    value: Some(raw[index + separator_length..].trim_start().to_string()),
+                    span: Span::default(),
+                }
+            }
+            None => Tag {
+                raw: raw.to_string(),
+                key: None,
+                value: None,
+                span: Span::default(),
+            },
+        }
+    }
+
+    /// the whole tag, trimmed, without its `#`
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    /// from the `#` to the end of the tag
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// `#speaker: Fogg` is named `speaker`, and `#surly` is named `surly`
+    pub fn is_named(&self, name: &str) -> bool {
+        self.key().unwrap_or(&self.raw) == name
+    }
+
+    pub(crate) fn resplit(&mut self, syntax: &TagSyntax) {
+        *self = Tag {
+            span: self.span,
+            ..Tag::parse(&self.raw, syntax)
+        };
+    }
+
+    pub(crate) fn with_span(self, span: Span) -> Self {
+        Tag { span, ..self }
+    }
+}
+
+impl From<&str> for Tag {
+    fn from(s: &str) -> Self {
+        Tag::parse(s, &TagSyntax::default())
+    }
+}
+
+/// The first tag named `name`, if there is one.
+pub(crate) fn find_tag<'a>(tags: &'a [Tag], name: &str) -> Option<&'a Tag> {
+    tags.iter().find(|tag| tag.is_named(name))
+}
@@ -1,67 +1,137 @@
 #[cfg(test)]
+use crate::expression::parse_expression;
+#[cfg(test)]
+use crate::span::{story_stream, StoryStream};
+#[cfg(test)]
 use crate::*;
 #[cfg(test)]
 use combine::easy::Error;
 #[cfg(test)]
 use combine::easy::Errors;
 #[cfg(test)]
-use combine::stream::PointerOffset;
-#[cfg(test)]
 use maplit::btreemap;
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 
-// TODO: use this to both make errors easier to read, and easier to test (and test that it works as it should...)
-//       BUT it seem like it's wrong, and the index will overflow...
-//       Could it be that the index is in bytes instead of ... other things?
 #[cfg(test)]
-fn pointer_offset_to_row_col(pointer_offset: PointerOffset<str>, text: &str) -> (usize, usize) {
-    let index = pointer_offset.translate_position(text);
+fn map_combine_error<'a>(
+    errors: Errors<char, &'a str, Location>,
+    text: &str,
+) -> ((usize, usize), Vec<Error<char, &'a str>>) {
+    (errors.position.line_column(text), errors.errors)
+}
 
-    index_to_row_col(index, text)
+/// Parses with the same kind of stream as `parse_story`, for parsers that keep track of spans
+#[cfg(test)]
+fn parse_with_positions<'a, P>(
+    mut parser: P,
+    text: &'a str,
+) -> Result<(P::Output, &'a str), Errors<char, &'a str, Location>>
+where
+    P: EasyParser<StoryStream<'a>>,
+    P::Output: WithoutSpans,
+{
+    parser
+        .easy_parse(story_stream(text, FileId::default()))
+        .map(|(output, rest)| (output.without_spans(), rest.input))
 }
 
+/// Nodes with their spans taken out, to compare them with nodes written out in a test.
+/// `test_spans` checks the spans themselves.
 #[cfg(test)]
-fn map_combine_error<'a>(
-    errors: Errors<char, &'a str, PointerOffset<str>>,
-    text: &str,
-) -> ((usize, usize), Vec<Error<char, &'a str>>) {
-    (
-        pointer_offset_to_row_col(errors.position, text),
-        errors.errors,
-    )
+trait WithoutSpans {
+    fn without_spans(self) -> Self;
 }
 
 #[cfg(test)]
-/// row and column are 1-indexed
-fn index_to_row_col(index: usize, text: &str) -> (usize, usize) {
-    if text.len() == 0 {
-        return (0, 0); // TODO: should this return an error instead? or return (1,1)?
+struct SpanStripper;
+
+#[cfg(test)]
+impl VisitMut for SpanStripper {
+    fn visit_knot_mut(&mut self, knot: &mut Knot) {
+        knot.span = Span::default();
+        visit::walk_knot_mut(self, knot);
+    }
+
+    fn visit_choice_mut(&mut self, choice: &mut Choice) {
+        choice.span = Span::default();
+        visit::walk_choice_mut(self, choice);
     }
 
-    if index > text.len() - 1 {
-        return (0, 1); // TODO: an error instead?
+    fn visit_dialog_line_mut(&mut self, line: &mut DialogLine) {
+        line.span = Span::default();
+        visit::walk_dialog_line_mut(self, line);
     }
 
-    dbg!(index);
+    fn visit_statement_mut(&mut self, statement: &mut Statement) {
+        statement.span = Span::default();
+        visit::walk_statement_mut(self, statement);
+    }
+
+    fn visit_divert_mut(&mut self, divert: &mut Spanned<Divert>) {
+        *divert = Spanned::from(divert.node().clone());
+    }
+
+    fn visit_tag_mut(&mut self, tag: &mut Tag) {
+        *tag = tag.clone().with_span(Span::default());
+    }
+}
+
+#[cfg(test)]
+macro_rules! without_spans {
+    ($($node:ty => $visit:ident),*) => {
+        $(
+            impl WithoutSpans for $node {
+                fn without_spans(mut self) -> Self {
+                    SpanStripper.$visit(&mut self);
+                    self
+                }
+            }
+        )*
+    };
+}
 
-    //let text = &;
-    let last_newline = text[0..index + 1].rfind('\n').unwrap_or(0);
-    let number_of_newlines = text[..index].chars().filter(|&c| c == '\n').count();
+#[cfg(test)]
+without_spans!(
+    Story => visit_story_mut,
+    Knot => visit_knot_mut,
+    KnotEnding => visit_knot_ending_mut,
+    Choice => visit_choice_mut,
+    DialogLine => visit_dialog_line_mut,
+    Statement => visit_statement_mut,
+    Spanned<Divert> => visit_divert_mut,
+    Tag => visit_tag_mut
+);
 
-    dbg!(last_newline);
-    dbg!(number_of_newlines);
+#[cfg(test)]
+impl<T: WithoutSpans> WithoutSpans for Vec<T> {
+    fn without_spans(self) -> Self {
+        self.into_iter().map(WithoutSpans::without_spans).collect()
+    }
+}
 
-    let row = number_of_newlines + 1;
-    let column = index - last_newline + 1 - if number_of_newlines > 0 { 1 } else { 0 };
+#[cfg(test)]
+impl WithoutSpans for StatementKind {
+    fn without_spans(self) -> Self {
+        self
+    }
+}
 
-    (row, column)
+#[cfg(test)]
+impl WithoutSpans for Body {
+    fn without_spans(self) -> Self {
+        Body {
+            tags: self.tags.without_spans(),
+            dialog_lines: self.dialog_lines.without_spans(),
+            statements: self.statements.without_spans(),
+        }
+    }
 }
 
 #[test]
 fn text_index_to_row_col() {
     fn find_x(text: &str) -> (usize, usize) {
-        index_to_row_col(text.find('X').unwrap(), text)
+        line_column(text, text.find('X').unwrap())
     }
 
     assert_eq!(find_x("X"), (1, 1));
@@ -80,55 +150,73 @@ fn test_dialog_line() {
     //color_backtrace::install();
 
     assert_eq!(
-        dialog_line().easy_parse("no line endings"),
+        parse_with_positions(dialog_line(), "no line endings"),
         Ok((
             DialogLine {
                 text: "no line endings".to_string(),
-                tags: vec![]
+                tags: vec![],
+                speaker: None,
+                speaker_prefix: None,
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        dialog_line().easy_parse("one line ending\n"),
+        parse_with_positions(dialog_line(), "one line ending\n"),
         Ok((
             DialogLine {
                 text: "one line ending".to_string(),
-                tags: vec![]
+                tags: vec![],
+                speaker: None,
+                speaker_prefix: None,
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        dialog_line().easy_parse("both line endings\r\n"),
+        parse_with_positions(dialog_line(), "both line endings\r\n"),
         Ok((
             DialogLine {
                 text: "both line endings".to_string(),
-                tags: vec![]
+                tags: vec![],
+                speaker: None,
+                speaker_prefix: None,
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        dialog_line().easy_parse("          line starting with spaces\r\n"),
+        parse_with_positions(dialog_line(), "          line starting with spaces\r\n"),
         Ok((
             DialogLine {
                 text: "line starting with spaces".to_string(),
-                tags: vec![]
+                tags: vec![],
+                speaker: None,
+                speaker_prefix: None,
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        dialog_line().easy_parse("       \n \r\n   line starting with newlines and spaces\r\n"),
+        parse_with_positions(
+            dialog_line(),
+            "       \n \r\n   line starting with newlines and spaces\r\n"
+        ),
         Ok((
             DialogLine {
                 text: "line starting with newlines and spaces".to_string(),
-                tags: vec![]
+                tags: vec![],
+                speaker: None,
+                speaker_prefix: None,
+                span: Span::default(),
             },
             ""
         ))
@@ -138,38 +226,75 @@ fn test_dialog_line() {
 #[test]
 fn test_divert() {
     assert_eq!(
-        divert().easy_parse("-> yeah"),
-        Ok((
-            Divert {
-                knot_title: "yeah".to_string()
-            },
-            ""
-        ))
+        parse_with_positions(divert(), "-> yeah"),
+        Ok((Divert::Knot("yeah".to_string()).into(), ""))
     );
 
     assert_eq!(
-        divert().easy_parse("    -> yeah"),
-        Ok((
-            Divert {
-                knot_title: "yeah".to_string()
-            },
-            ""
-        ))
+        parse_with_positions(divert(), "    -> yeah"),
+        Ok((Divert::Knot("yeah".to_string()).into(), ""))
     );
 
-    //let text = "===";
-    //assert_eq!(
-    //    pointer_offset_to_row_col(divert().easy_parse(text).unwrap_err().position, text),
-    //    (1, 1)
-    //);
+    assert_eq!(
+        parse_with_positions(divert(), "-> END"),
+        Ok((Divert::End.into(), ""))
+    );
+    assert_eq!(
+        parse_with_positions(divert(), "-> DONE"),
+        Ok((Divert::Done.into(), ""))
+    );
+    assert_eq!(
+        parse_with_positions(divert(), "->->"),
+        Ok((Divert::TunnelReturn.into(), ""))
+    );
+    assert_eq!(
+        parse_with_positions(divert(), "->->  // back\nnext"),
+        Ok((Divert::TunnelReturn.into(), "next"))
+    );
+    assert!(parse_with_positions(divert(), "->-> elsewhere").is_err());
+    assert_eq!(
+        parse_with_positions(divert(), "-> paris.downtown\n"),
+        Ok((Divert::Knot("paris.downtown".to_string()).into(), ""))
+    );
+
+    let story = parse_story(
+        "VAR destination = -> paris
+-> destination
+
+=== paris
+~ temp next = -> END
++ onwards
+    -> next
+",
+    )
+    .without_spans();
+    assert_eq!(
+        story.knot("INTRO").unwrap().ending,
+        KnotEnding::DIVERT(Divert::Variable("destination".to_string()).into())
+    );
+    assert_eq!(
+        story.knot("paris").unwrap().ending,
+        KnotEnding::CHOICES(vec![Choice {
+            text: "onwards".to_string(),
+            dialog_lines: vec![],
+            statements: vec![],
+            divert: Divert::Variable("next".to_string()).into(),
+            span: Span::default(),
+        }])
+    );
+
+    let text = "===";
+    assert_eq!(
+        map_combine_error(parse_with_positions(divert(), text).unwrap_err(), text).0,
+        (1, 1)
+    );
 }
 
 #[test]
 fn test_choice() {
     let text = "-> divert";
     assert_eq!(
-        parse_choice()
-            .easy_parse(text)
+        parse_with_positions(parse_choice(), text)
             .map_err(|e| map_combine_error(e, text))
             .unwrap_err()
             .0,
@@ -177,70 +302,70 @@ fn test_choice() {
     );
 
     assert_eq!(
-        parse_choice().easy_parse("+ yeah\n-> divert"),
+        parse_with_positions(parse_choice(), "+ yeah\n-> divert"),
         Ok((
             Choice {
                 text: "yeah".to_string(),
                 dialog_lines: vec![],
-                divert: Divert {
-                    knot_title: "divert".to_string()
-                }
+                statements: vec![],
+                divert: Divert::Knot("divert".to_string()).into(),
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        parse_choice().easy_parse("+ yeah\n   -> divert"),
+        parse_with_positions(parse_choice(), "+ yeah\n   -> divert"),
         Ok((
             Choice {
                 text: "yeah".to_string(),
                 dialog_lines: vec![],
-                divert: Divert {
-                    knot_title: "divert".to_string()
-                }
+                statements: vec![],
+                divert: Divert::Knot("divert".to_string()).into(),
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        parse_choice().easy_parse("+ yeah\n  one\ntwo\n     three\n-> divert"),
+        parse_with_positions(parse_choice(), "+ yeah\n  one\ntwo\n     three\n-> divert"),
         Ok((
             Choice {
                 text: "yeah".to_string(),
                 dialog_lines: vec!["one".into(), "two".into(), "three".into()],
-                divert: Divert {
-                    knot_title: "divert".to_string()
-                }
+                statements: vec![],
+                divert: Divert::Knot("divert".to_string()).into(),
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        parse_choice().easy_parse("+ yeah\n  one\ntwo\n-> paris"),
+        parse_with_positions(parse_choice(), "+ yeah\n  one\ntwo\n-> paris"),
         Ok((
             Choice {
                 text: "yeah".to_string(),
                 dialog_lines: vec!["one".into(), "two".into()],
-                divert: Divert {
-                    knot_title: "paris".to_string()
-                }
+                statements: vec![],
+                divert: Divert::Knot("paris".to_string()).into(),
+                span: Span::default(),
             },
             ""
         ))
     );
 
     assert_eq!(
-        parse_choice().easy_parse("+ yeah\n  one\ntwo\n   -> paris"),
+        parse_with_positions(parse_choice(), "+ yeah\n  one\ntwo\n   -> paris"),
         Ok((
             Choice {
                 text: "yeah".to_string(),
                 dialog_lines: vec!["one".into(), "two".into()],
-                divert: Divert {
-                    knot_title: "paris".to_string()
-                }
+                statements: vec![],
+                divert: Divert::Knot("paris".to_string()).into(),
+                span: Span::default(),
             },
             ""
         ))
@@ -248,14 +373,14 @@ fn test_choice() {
 
     // consume and ignore empty dialog lines
     assert_eq!(
-        parse_choice().easy_parse("+ yeah\n  \n  one\n   \ntwo\n   -> paris"),
+        parse_with_positions(parse_choice(), "+ yeah\n  \n  one\n   \ntwo\n   -> paris"),
         Ok((
             Choice {
                 text: "yeah".to_string(),
                 dialog_lines: vec!["one".into(), "two".into()],
-                divert: Divert {
-                    knot_title: "paris".to_string()
-                }
+                statements: vec![],
+                divert: Divert::Knot("paris".to_string()).into(),
+                span: Span::default(),
             },
             ""
         ))
@@ -265,105 +390,137 @@ fn test_choice() {
 #[test]
 fn test_story() {
     assert_eq!(
-        story().easy_parse(include_str!("../stories/two_knots.ink")),
+        parse_with_positions(story(), include_str!("../stories/two_knots.ink")),
         Ok((
             Story {
-                knots: btreemap! {
-                    "INTRO".to_string() => Knot {
+                global_tags: vec![],
+                variables: btreemap! {},
+                externals: btreemap! {},
+                knots: vec![
+                    Knot {
                         title: "INTRO".to_string(),
-                        dialog_lines: vec![
-                            "to paris".into()
-                        ],
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
+                        dialog_lines: vec!["to paris".into()],
+                        statements: vec![],
                         ending: KnotEnding::DIVERT("paris".into()),
+                        span: Span::default(),
                     },
-                    "paris".to_string() => Knot {
+                    Knot {
                         title: "paris".to_string(),
-                        dialog_lines: vec![
-                            "We are in paris.".into()
-                        ],
-                        ending: KnotEnding::DIVERT(
-                            "ending".into()
-                        ),
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
+                        dialog_lines: vec!["We are in paris.".into()],
+                        statements: vec![],
+                        ending: KnotEnding::DIVERT("ending".into()),
+                        span: Span::default(),
                     },
-                    "ending".to_string() => Knot {
+                    Knot {
                         title: "ending".to_string(),
-                        dialog_lines: vec![
-                            "THE END now.".into()
-                        ],
-                        ending: KnotEnding::DIVERT(
-                            "END".into()
-                        )
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
+                        dialog_lines: vec!["THE END now.".into()],
+                        statements: vec![],
+                        ending: KnotEnding::DIVERT(Divert::End.into()),
+                        span: Span::default(),
                     },
-                }
+                ]
             },
             ""
         ))
     );
 
     assert_eq!(
-        story().easy_parse(include_str!("../stories/two_knots_with_choices.ink")),
+        parse_with_positions(
+            story(),
+            include_str!("../stories/two_knots_with_choices.ink")
+        ),
         Ok((
             Story {
-                knots: btreemap! {
-                    "INTRO".to_string() => Knot {
+                global_tags: vec![],
+                variables: btreemap! {},
+                externals: btreemap! {},
+                knots: vec![
+                    Knot {
                         title: "INTRO".to_string(),
-                        dialog_lines: vec![
-                            "to paris?".into()
-                        ],
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
+                        dialog_lines: vec!["to paris?".into()],
+                        statements: vec![],
                         ending: KnotEnding::CHOICES(vec![
                             Choice {
                                 text: "yeah".to_string(),
                                 dialog_lines: vec!["yes, please".into()],
+                                statements: vec![],
                                 divert: "paris".into(),
+                                span: Span::default(),
                             },
                             Choice {
                                 text: "no".to_string(),
                                 dialog_lines: vec!["no, thank you".into()],
+                                statements: vec![],
                                 divert: "ending".into(),
+                                span: Span::default(),
                             }
                         ]),
+                        span: Span::default(),
                     },
-                    "paris".to_string() => Knot {
+                    Knot {
                         title: "paris".to_string(),
-                        dialog_lines: vec![
-                            "We are in paris.".into()
-                        ],
-                        ending: KnotEnding::DIVERT(
-                            "ending".into()
-                        ),
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
+                        dialog_lines: vec!["We are in paris.".into()],
+                        statements: vec![],
+                        ending: KnotEnding::DIVERT("ending".into()),
+                        span: Span::default(),
                     },
-                    "ending".to_string() => Knot {
+                    Knot {
                         title: "ending".to_string(),
-                        dialog_lines: vec![
-                            "THE END now.".into()
-                        ],
-                        ending: KnotEnding::DIVERT(
-                            "END".into()
-                        )
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
+                        dialog_lines: vec!["THE END now.".into()],
+                        statements: vec![],
+                        ending: KnotEnding::DIVERT(Divert::End.into()),
+                        span: Span::default(),
                     },
-                }
+                ]
             },
             ""
         ))
     );
 
     assert_eq!(
-        story().easy_parse(include_str!("../stories/basic_story.ink")),
+        parse_with_positions(story(), include_str!("../stories/basic_story.ink")),
         Ok((
             Story {
-                knots: btreemap! {
-                    "INTRO".to_string() => Knot {
+                global_tags: vec![],
+                variables: btreemap! {},
+                externals: btreemap! {},
+                knots: vec![
+                    Knot {
                         title: "INTRO".to_string(),
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
                         dialog_lines: vec![
                             "Want to go to paris?".into(),
                             "PLEASE!?".into(),
                             "will you?????????".into(),
                         ],
+                        statements: vec![],
                         ending: KnotEnding::CHOICES(vec![
                             Choice {
                                 text: "yeah!".to_string(),
                                 dialog_lines: vec![],
+                                statements: vec![],
                                 divert: "paris".into(),
+                                span: Span::default(),
                             },
                             Choice {
                                 text: "\"Around the world, Monsieur?\"".to_string(),
@@ -374,53 +531,68 @@ fn test_story() {
                                         "OOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOOO",
                                         "OOOOOOOOOOOOOOOOOOOOOOOONG!").into(),
                                 ],
+                                statements: vec![],
                                 divert: "ending".into(),
+                                span: Span::default(),
                             },
                         ]),
+                        span: Span::default(),
                     },
-                    "paris".to_string() => Knot {
+                    Knot {
                         title: "paris".to_string(),
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
                         dialog_lines: vec![
                             "We are in paris.".into()
                         ],
+                        statements: vec![],
                         ending: KnotEnding::DIVERT(
                             "ending".into()
                         ),
+                        span: Span::default(),
                     },
-                    "ending".to_string() => Knot {
+                    Knot {
                         title: "ending".to_string(),
+                        parameters: vec![],
+                        is_function: false,
+                        tags: vec![],
                         dialog_lines: vec![
                             "THE END now.".into()
                         ],
-                        ending: KnotEnding::DIVERT(
-                            "END".into()
-                        )
+                        statements: vec![],
+                        ending: KnotEnding::DIVERT(Divert::End.into()),
+                        span: Span::default(),
                     },
-                }
+                ]
             },
             ""
         ))
     );
 
     assert_eq!(
-        story().easy_parse(include_str!("../stories/spaces_before_divert.ink")),
+        parse_with_positions(story(), include_str!("../stories/spaces_before_divert.ink")),
         Ok((
             Story {
-                knots: btreemap! {
-                    "INTRO".to_string() => Knot {
-                        title: "INTRO".to_string(),
-                        dialog_lines: vec![
-                            "a thing".into()
-                        ],
-                        ending: KnotEnding::CHOICES(vec![
-                            Choice {
-                                text: "🙁".to_string(),
-                                dialog_lines: vec![],
-                                divert: "ending".into(),
-                            }
-                        ]),
-                    }
-                }
+                global_tags: vec![],
+                variables: btreemap! {},
+                externals: btreemap! {},
+                knots: vec![Knot {
+                    title: "INTRO".to_string(),
+                    parameters: vec![],
+                    is_function: false,
+                    tags: vec![],
+                    dialog_lines: vec!["a thing".into()],
+                    statements: vec![],
+                    ending: KnotEnding::CHOICES(vec![Choice {
+                        text: "🙁".to_string(),
+                        dialog_lines: vec![],
+                        statements: vec![],
+                        divert: "ending".into(),
+                        span: Span::default(),
+                    }]),
+                    span: Span::default(),
+                }]
             },
             ""
         ))
@@ -428,7 +600,7 @@ fn test_story() {
 
     // TODO: need to have "stitches" (sub knots) first
     //assert_eq!(
-    //    story().easy_parse(include_str!("../stories/too_many_blank_lines.ink")),
+    //    parse_with_positions(story(), include_str!("../stories/too_many_blank_lines.ink")),
     //    Ok((Story::default(), ""))
     //);
 }
@@ -451,12 +623,18 @@ fn test_comments() {
     );
 
     assert_eq!(
-        rest_of_the_line_ignoring_comments_with_tags().easy_parse("text // comment"),
+        parse_with_positions(
+            rest_of_the_line_ignoring_comments_with_tags(),
+            "text // comment"
+        ),
         Ok(("text".into(), ""))
     );
 
     assert_eq!(
-        rest_of_the_line_ignoring_comments_with_tags().easy_parse("text /* comment */"),
+        parse_with_positions(
+            rest_of_the_line_ignoring_comments_with_tags(),
+            "text /* comment */"
+        ),
         Ok(("text".into(), ""))
     );
 
@@ -466,34 +644,41 @@ fn test_comments() {
     );
 
     assert_eq!(
-        dialog_lines().easy_parse("text /* comment\n */"),
+        parse_with_positions(dialog_lines(), "text /* comment\n */"),
         Ok((vec!["text".into()], ""))
     );
 
     assert_eq!(
-        dialog_lines().easy_parse("text /* comment\n */\ncool // comment 2\n yeah"),
+        parse_with_positions(
+            dialog_lines(),
+            "text /* comment\n */\ncool // comment 2\n yeah"
+        ),
         Ok((vec!["text".into(), "cool".into(), "yeah".into()], ""))
     );
 
     // TODO: dialog_lines() where we _start_ with a comment or multi line comment
 
     assert_eq!(
-        dialog_lines().easy_parse("dialog 1\n// comment 1\ndialog 2"),
+        parse_with_positions(dialog_lines(), "dialog 1\n// comment 1\ndialog 2"),
         Ok((vec!["dialog 1".into(), "dialog 2".into()], ""))
     );
 
     assert_eq!(
-        dialog_lines().easy_parse("text /* comment\n */\ncool // comment 2\n yeah"),
+        parse_with_positions(
+            dialog_lines(),
+            "text /* comment\n */\ncool // comment 2\n yeah"
+        ),
         Ok((vec!["text".into(), "cool".into(), "yeah".into()], ""))
     );
 
     assert_eq!(
-        dialog_lines().easy_parse("dialog /* comment 4 */4"),
+        parse_with_positions(dialog_lines(), "dialog /* comment 4 */4"),
         Ok((vec!["dialog 4".into()], ""))
     );
 
     assert_eq!(
-        dialog_lines().easy_parse(
+        parse_with_positions(
+            dialog_lines(),
             "dialog 1
 // comment 1
 dialog 2
@@ -515,7 +700,8 @@ dialog /* comment 4 */4"
     );
 
     assert_eq!(
-        dialog_lines().easy_parse(
+        parse_with_positions(
+            dialog_lines(),
             "dialog 1
 -> END"
         ),
@@ -531,7 +717,8 @@ dialog /* comment 4 */4"
     );
 
     assert_eq!(
-        dialog_lines().easy_parse(
+        parse_with_positions(
+            dialog_lines(),
             "dialog 1
 // comment 1
 cool
@@ -540,90 +727,104 @@ cool
         Ok((vec!["dialog 1".into(), "cool".into()], "-> END"))
     );
 
-    //    assert_eq!(
-    //        dialog_lines().easy_parse(
-    //            "dialog 1
-    //// comment 1
-    //-> END"
-    //        ),
-    //        Ok((vec!["dialog 1".into(),], "-> END"))
-    //    );
-
-    //    assert_eq!(
-    //        knot_without_title().easy_parse(
-    //            "dialog 1
-    //// comment 1
-    //-> END"
-    //        ),
-    //        Ok((
-    //            Knot {
-    //                title: "INTRO".to_string(),
-    //                dialog_lines: vec!["dialog 1".into()],
-    //                ending: KnotEnding::DIVERT("END".into())
-    //            },
-    //            ""
-    //        ))
-    //    );
-
-    //    //    assert_eq!(
-    //    //        knot_without_title().easy_parse(
-    //    //            "dialog 1
-    //    //// comment 1
-    //    //dialog 2
-    //    ///*
-    //    //    comment 2
-    //    //*/
-    //    //dialog 3 // comment 3
-    //    //dialog /* comment 4 */4
-    //    //-> END"
-    //    //        ),
-    //    //        Ok((
-    //    //            Knot {
-    //    //                title: "INTRO".to_string(),
-    //    //                dialog_lines: vec![
-    //    //                    "dialog 1".into(),
-    //    //                    "dialog 2".into(),
-    //    //                    "dialog 3".into(),
-    //    //                    "dialog 4".into()
-    //    //                ],
-    //    //                ending: KnotEnding::DIVERT("END".into())
-    //    //            },
-    //    //            ""
-    //    //        ))
-    //    //    );
-
-    ////    assert_eq!(
-    ////        story().easy_parse(
-    ////            "dialog 1
-    ////// comment 1
-    ////dialog 2
-    /////*
-    ////    comment 2
-    ////*/
-    ////dialog 3 // comment 3
-    ////dialog /* comment 4 */4
-    ////-> END"
-    ////        ),
-    ////        Ok((
-    ////            Story {
-    ////                knots: btreemap! {
-    ////                    "INTRO".to_string() => Knot {
-    ////                        title: "INTRO".to_string(),
-    ////                        dialog_lines: vec![
-    ////                            "dialog 1".into(),
-    ////                            "dialog 2".into(),
-    ////                            "dialog 3".into(),
-    ////                            "dialog 4".into(),
-    ////                        ],
-    ////                        ending: KnotEnding::DIVERT(
-    ////                            "END".into()
-    ////                        ),
-    ////                    }
-    ////                }
-    ////            },
-    ////            ""
-    ////        ))
-    ////    );
+    assert_eq!(
+        parse_with_positions(
+            dialog_lines(),
+            "dialog 1
+// comment 1
+-> END"
+        ),
+        Ok((vec!["dialog 1".into(),], "-> END"))
+    );
+
+    assert_eq!(
+        parse_with_positions(
+            knot_without_title(),
+            "dialog 1
+// comment 1
+-> END"
+        ),
+        Ok((
+            Knot {
+                title: "INTRO".to_string(),
+                parameters: vec![],
+                is_function: false,
+                dialog_lines: vec!["dialog 1".into()],
+                ending: KnotEnding::DIVERT("END".into()),
+                ..Knot::default()
+            },
+            ""
+        ))
+    );
+
+    assert_eq!(
+        parse_with_positions(
+            story(),
+            "// comment 0
+dialog 1
+// comment 1
+dialog 2
+/*
+    comment 2
+*/
+dialog 3 // comment 3
+dialog /* comment 4 */4
+dialog 5 /* comment
+   5 */ still 5 # tag // comment 6
+a/b is not a comment
++ choice // comment 7
+    -> knot // comment 8
+
+=== knot /* comment 9 */ === // comment 10
+-> END // comment 11
+// comment 12
+"
+        ),
+        Ok((
+            Story {
+                global_tags: vec![],
+                variables: btreemap! {},
+                externals: btreemap! {},
+                knots: vec![
+                    Knot {
+                        title: "INTRO".to_string(),
+                        parameters: vec![],
+                        is_function: false,
+                        dialog_lines: vec![
+                            "dialog 1".into(),
+                            "dialog 2".into(),
+                            "dialog 3".into(),
+                            "dialog 4".into(),
+                            DialogLine {
+                                text: "dialog 5  still 5".to_string(),
+                                tags: vec!["tag".into()],
+                                speaker: None,
+                                speaker_prefix: None,
+                                span: Span::default(),
+                            },
+                            "a/b is not a comment".into(),
+                        ],
+                        ending: KnotEnding::CHOICES(vec![Choice {
+                            text: "choice".to_string(),
+                            dialog_lines: vec![],
+                            statements: vec![],
+                            divert: "knot".into(),
+                            span: Span::default(),
+                        }]),
+                        ..Knot::default()
+                    },
+                    Knot {
+                        title: "knot".to_string(),
+                        parameters: vec![],
+                        is_function: false,
+                        ending: KnotEnding::DIVERT("END".into()),
+                        ..Knot::default()
+                    },
+                ]
+            },
+            ""
+        ))
+    );
 }
 
 #[test]
@@ -652,22 +853,1796 @@ fn test_knot() {
 
 #[test]
 fn test_tags() {
-    assert!(tag().easy_parse("no line endings").is_err());
+    assert!(parse_with_positions(tag(), "no line endings").is_err());
 
     assert_eq!(
-        many1::<Vec<String>, _, _>(tag()).easy_parse("# one # two"),
+        parse_with_positions(many1::<Vec<Tag>, _, _>(tag()), "# one # two"),
         Ok((vec!["one".into(), "two".into()], ""))
     );
 
     assert_eq!(
-        dialog_line().easy_parse("Passepartout: Really, Monsieur. # surly # really_monsieur.ogg"),
+        parse_with_positions(
+            dialog_line(),
+            "Passepartout: Really, Monsieur. # surly # really_monsieur.ogg"
+        ),
         Ok((
             DialogLine {
                 text: "Passepartout: Really, Monsieur.".to_string(),
-                tags: vec!["surly".into(), "really_monsieur.ogg".into()]
-            }
-            .into(),
+                tags: vec!["surly".into(), "really_monsieur.ogg".into()],
+                speaker: None,
+                speaker_prefix: None,
+                span: Span::default(),
+            },
+            ""
+        ))
+    );
+}
+
+#[test]
+fn test_expressions() {
+    assert_eq!(
+        parse_expression().easy_parse("health - 2 * 3"),
+        Ok((
+            Expression::Binary(
+                Box::new(Expression::Variable("health".into())),
+                BinaryOperator::Subtract,
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Int(2)),
+                    BinaryOperator::Multiply,
+                    Box::new(Expression::Int(3))
+                ))
+            ),
+            ""
+        ))
+    );
+
+    assert_eq!(
+        parse_expression().easy_parse("not seen_clue && TURNS() >= 1.5"),
+        Ok((
+            Expression::Binary(
+                Box::new(Expression::Unary(
+                    UnaryOperator::Not,
+                    Box::new(Expression::Variable("seen_clue".into()))
+                )),
+                BinaryOperator::And,
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Call("TURNS".into(), vec![])),
+                    BinaryOperator::GreaterOrEqual,
+                    Box::new(Expression::Float(1.5))
+                ))
+            ),
+            ""
+        ))
+    );
+
+    assert_eq!(
+        parse_with_positions(statement().map(|(kind, _)| kind), "~ temp x = 5\n"),
+        Ok((
+            StatementKind::TempDeclaration("x".into(), Expression::Int(5)),
+            ""
+        ))
+    );
+
+    assert_eq!(
+        parse_with_positions(statement().map(|(kind, _)| kind), "~ health -= 2"),
+        Ok((
+            StatementKind::SubtractAssignment("health".into(), Expression::Int(2)),
+            ""
+        ))
+    );
+
+    assert_eq!(
+        parse_with_positions(body(), "one\n~ x = -> paris\ntwo\n~ play()"),
+        Ok((
+            Body {
+                tags: vec![],
+                dialog_lines: vec!["one".into(), "two".into()],
+                statements: vec![
+                    Statement {
+                        before_line: 1,
+                        span: Span::default(),
+                        kind: StatementKind::Assignment(
+                            "x".into(),
+                            Expression::DivertTarget("paris".into())
+                        )
+                    },
+                    Statement {
+                        before_line: 2,
+                        span: Span::default(),
+                        kind: StatementKind::Expression(Expression::Call("play".into(), vec![]))
+                    }
+                ]
+            },
             ""
         ))
     );
 }
+
+#[test]
+fn test_temp_scope() {
+    let story = parse_story(
+        "VAR health = 100
+~ temp bonus = 5
+~ health += bonus
+-> paris
+
+=== paris
+~ temp health = 1
+Back to {bonus}.
+-> END
+",
+    );
+
+    assert_eq!(
+        story.variables,
+        btreemap! {"health".to_string() => Expression::Int(100)}
+    );
+
+    assert_eq!(
+        check_story(&story),
+        vec![
+            StoryError::TempShadowsGlobal {
+                name: "health".into(),
+                knot: "paris".into()
+            },
+            StoryError::TempOutOfScope {
+                name: "bonus".into(),
+                declared_in: "INTRO".into(),
+                used_in: "paris".into()
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_temp_scope_with_duplicate_knots() {
+    let story = parse_story(
+        "VAR v = 0
+-> a
+=== a
+~ temp v = 1
+~ temp w = 2
+-> b
+=== a
+hi
+-> END
+=== b
+{w}
+-> END
+",
+    )
+    .without_spans();
+
+    let errors: Vec<StoryError> = check_story(&story)
+        .into_iter()
+        .filter(|error| !matches!(error, StoryError::DuplicateKnot { .. }))
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            StoryError::TempShadowsGlobal {
+                name: "v".into(),
+                knot: "a".into()
+            },
+            StoryError::TempOutOfScope {
+                name: "w".into(),
+                declared_in: "a".into(),
+                used_in: "b".into()
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_tags_on_their_own_lines() {
+    // story_with_variables.ink, without the choice that diverts on the same line
+    let story = parse_story(
+        "LONDON, 1872
+-> paris
+
+=== paris
+= downtown
+# downtown tag
+It was cool downtown. #tag ya
+-> london
+
+=== london ===
+# health +1
+Monsieur Phileas Fogg returned home early from the Reform Club. #tag1 #tag2
+health: \"{health}\"
+# tag 4
+\"Passepartout,\" said he. \"We are going around the world!\" #tag 3
+-> END
+",
+    )
+    .without_spans();
+
+    assert_eq!(story.knot("london").unwrap().tags, vec!["health +1".into()]);
+    assert_eq!(
+        story.knot("london").unwrap().dialog_lines[2],
+        DialogLine {
+            text: "\"Passepartout,\" said he. \"We are going around the world!\"".to_string(),
+            tags: vec!["tag 4".into(), "tag 3".into()],
+            speaker: None,
+            speaker_prefix: None,
+            span: Span::default(),
+        }
+    );
+    assert_eq!(
+        story.knot("paris").unwrap().dialog_lines[1],
+        DialogLine {
+            text: "It was cool downtown.".to_string(),
+            tags: vec!["downtown tag".into(), "tag ya".into()],
+            speaker: None,
+            speaker_prefix: None,
+            span: Span::default(),
+        }
+    );
+    assert_eq!(check_story(&story), vec![]);
+
+    // VAR isn't content, so tags after it are still global
+    let story = parse_story("# title: Around the World\nVAR x = 1\n# author: Verne\nhello\n-> END")
+        .without_spans();
+    assert_eq!(
+        story.global_tags,
+        vec!["title: Around the World".into(), "author: Verne".into()]
+    );
+    assert_eq!(story.knot("INTRO").unwrap().tags, vec![]);
+}
+
+#[test]
+fn test_tag_keys_and_values() {
+    let line = parse_with_positions(
+        dialog_line(),
+        "We leave at 8:25. #speaker: Fogg #audio:vo_001.ogg #camera close #time: 8:25 pm #surly",
+    )
+    .unwrap()
+    .0;
+
+    assert_eq!(line.tag("speaker").and_then(Tag::value), Some("Fogg"));
+    assert_eq!(line.tag("audio").and_then(Tag::value), Some("vo_001.ogg"));
+    assert_eq!(line.tag("camera").and_then(Tag::value), Some("close"));
+    assert_eq!(line.tag("time").and_then(Tag::value), Some("8:25 pm"));
+    assert_eq!(line.tag("surly").map(Tag::raw), Some("surly"));
+    assert_eq!(line.tag("surly").and_then(Tag::key), None);
+    assert_eq!(line.tag("missing"), None);
+
+    let mut story = parse_story("# title = Around the World\nhello #mood=grim\n-> END");
+    assert_eq!(
+        story.global_tag("title").and_then(Tag::value),
+        Some("= Around the World")
+    );
+
+    story.split_tags(&TagSyntax::new(&['=']));
+    assert_eq!(
+        story.global_tag("title").and_then(Tag::value),
+        Some("Around the World")
+    );
+    assert_eq!(
+        story.knot("INTRO").unwrap().dialog_lines[0]
+            .tag("mood")
+            .and_then(Tag::value),
+        Some("grim")
+    );
+}
+
+#[test]
+fn test_speakers() {
+    let mut story = parse_story(
+        "# characters: Fogg, Passepartout
+Fogg: \"We leave at 8:25.\"
+Passepartout: Really, Monsieur. # surly
+health: \"{health}\"
+It is 8:25.
+-> END",
+    )
+    .without_spans();
+    story.extract_speakers(&SpeakerSyntax::default());
+
+    assert_eq!(
+        story.characters(&SpeakerSyntax::default()),
+        vec!["Fogg".to_string(), "Passepartout".to_string()]
+    );
+    assert_eq!(
+        story.knot("INTRO").unwrap().dialog_lines,
+        vec![
+            DialogLine {
+                text: "\"We leave at 8:25.\"".to_string(),
+                tags: vec![],
+                speaker: Some("Fogg".to_string()),
+                speaker_prefix: Some("Fogg: ".to_string()),
+                span: Span::default(),
+            },
+            DialogLine {
+                text: "Really, Monsieur.".to_string(),
+                tags: vec!["surly".into()],
+                speaker: Some("Passepartout".to_string()),
+                speaker_prefix: Some("Passepartout: ".to_string()),
+                span: Span::default(),
+            },
+            "health: \"{health}\"".into(),
+            "It is 8:25.".into(),
+        ]
+    );
+
+    // without a characters tag, nobody is a speaker
+    let mut story = parse_story("Fogg - Hello.\n-> END");
+    story.extract_speakers(&SpeakerSyntax::new("-", "characters"));
+    assert_eq!(story.knot("INTRO").unwrap().dialog_lines[0].speaker, None);
+
+    let mut story = parse_story("# cast: Fogg\nFogg - Hello.\n-> END");
+    story.extract_speakers(&SpeakerSyntax::new(" - ", "cast"));
+    assert_eq!(
+        story.knot("INTRO").unwrap().dialog_lines[0]
+            .clone()
+            .without_spans(),
+        DialogLine {
+            text: "Hello.".to_string(),
+            tags: vec![],
+            speaker: Some("Fogg".to_string()),
+            speaker_prefix: Some("Fogg - ".to_string()),
+            span: Span::default(),
+        }
+    );
+    // compiled, the line is written the way it was, separator and all
+    let compiled: serde_json::Value = serde_json::from_str(&compile_story(&story)).unwrap();
+    assert_eq!(compiled["root"][2]["INTRO"][0], "^Fogg - Hello.");
+}
+
+#[test]
+fn test_implicit_endings() {
+    let story = parse_story(
+        "Once upon a time.
+-> middle
+
+=== middle
+The content just stops.
+
+=== empty ===
+=== last
+Only text here.
+",
+    )
+    .without_spans();
+
+    assert_eq!(
+        story.knot("middle").unwrap(),
+        &Knot {
+            title: "middle".to_string(),
+            dialog_lines: vec!["The content just stops.".into()],
+            ending: KnotEnding::IMPLICIT,
+            ..Knot::default()
+        }
+    );
+    assert_eq!(story.knot("empty").unwrap().ending, KnotEnding::IMPLICIT);
+    assert_eq!(
+        story.knot("last").unwrap().dialog_lines,
+        vec!["Only text here.".into()]
+    );
+
+    assert_eq!(check_story(&story), vec![]);
+    assert_eq!(
+        story_warnings(&story),
+        vec![
+            StoryWarning::LooseEnd {
+                knot: "middle".into()
+            },
+            StoryWarning::LooseEnd {
+                knot: "empty".into()
+            },
+            StoryWarning::LooseEnd {
+                knot: "last".into()
+            },
+        ]
+    );
+
+    assert_eq!(
+        story_warnings(&parse_story(include_str!("../stories/two_knots.ink"))),
+        vec![]
+    );
+}
+
+#[test]
+fn test_knot_order_and_duplicates() {
+    let text = "-> zebra
+
+=== zebra
+-> apple
+
+=== apple
+First apple.
+-> zebra
+
+=== apple
+Second apple.
+-> END
+";
+    let story = parse_story(text);
+
+    assert_eq!(
+        story
+            .knots
+            .iter()
+            .map(|knot| knot.title.as_str())
+            .collect::<Vec<_>>(),
+        vec!["INTRO", "zebra", "apple", "apple"]
+    );
+    assert_eq!(
+        story
+            .knot("apple")
+            .unwrap()
+            .dialog_lines
+            .clone()
+            .without_spans(),
+        vec!["First apple.".into()]
+    );
+
+    let errors = check_story(&story);
+    assert_eq!(errors.len(), 1);
+    if let StoryError::DuplicateKnot {
+        title,
+        first,
+        second,
+    } = &errors[0]
+    {
+        assert_eq!(title, "apple");
+        assert_eq!(first.line_column(text), (6, 1));
+        assert_eq!(second.line_column(text), (10, 1));
+        assert_eq!(
+            &text[second.start()..second.end()],
+            "=== apple\nSecond apple.\n-> END\n"
+        );
+    }
+}
+
+#[test]
+fn test_spans() {
+    let text = "Hello, Monsieur. #greeting
+~ temp late = true
+
+=== paris
+Paris! /* at last */
++ Café 🙂
+    -> END
+";
+    let story = parse_story_in_file(text, FileId(3));
+    let intro = story.knot("INTRO").unwrap();
+    let paris = story.knot("paris").unwrap();
+    let slice = |span: Span| &text[span.start()..span.end()];
+
+    let greeting = &intro.dialog_lines[0];
+    assert_eq!(slice(greeting.span()), "Hello, Monsieur. #greeting");
+    assert_eq!(greeting.span().file(), FileId(3));
+    assert_eq!(slice(greeting.tags[0].span()), "#greeting");
+    assert_eq!(intro.statements[0].span().line_column(text), (2, 1));
+
+    assert_eq!(paris.span().line_column(text), (4, 1));
+    assert_eq!(paris.dialog_lines[0].span().line_column(text), (5, 1));
+    match &paris.ending {
+        KnotEnding::CHOICES(choices) => {
+            assert_eq!(choices[0].span().line_column(text), (6, 1));
+            assert_eq!(choices[0].divert.span().line_column(text), (7, 5));
+            assert_eq!(*choices[0].divert, Divert::End);
+        }
+        ending => panic!("expected choices, got {:?}", ending),
+    }
+
+    // columns count chars, not bytes
+    assert_eq!(
+        line_column("Café 🙂 X", "Café 🙂 X".find('X').unwrap()),
+        (1, 8)
+    );
+    // an offset in the middle of a char is that char
+    assert_eq!(line_column("é", 1), (1, 1));
+    assert_eq!(line_column("aé\nb", 2), (1, 2));
+}
+
+#[test]
+fn test_public_api() {
+    let story = parse_story(
+        "# title: Around the World
+VAR health = 3
+Fogg: We leave at 8:25. #urgent
+~ health -= 1
+-> london
+
+=== london
++ Hail a cab
+    Passepartout: To the station!
+    -> END
++ Walk
+    -> DONE
+",
+    )
+    .without_spans();
+
+    let built = Story::new()
+        .with_global_tag("title: Around the World".into())
+        .with_variable("health", Expression::Int(3))
+        .with_knot(
+            Knot::new("INTRO")
+                .with_line(DialogLine::new("Fogg: We leave at 8:25.").with_tag("urgent".into()))
+                .with_statement(StatementKind::SubtractAssignment(
+                    "health".into(),
+                    Expression::Int(1),
+                ))
+                .with_divert("london".into()),
+        )
+        .with_knot(
+            Knot::new("london")
+                .with_choice(
+                    Choice::new("Hail a cab", Divert::End)
+                        .with_line(DialogLine::new("Passepartout: To the station!")),
+                )
+                .with_choice(Choice::new("Walk", Divert::Done)),
+        );
+    assert_eq!(story, built);
+
+    assert_eq!(story.global_tags()[0].value(), Some("Around the World"));
+    assert_eq!(story.variables().get("health"), Some(&Expression::Int(3)));
+    assert_eq!(
+        story.knots().iter().map(Knot::title).collect::<Vec<_>>(),
+        vec!["INTRO", "london"]
+    );
+
+    let intro = &story.knots()[0];
+    assert_eq!(intro.dialog_lines()[0].text(), "Fogg: We leave at 8:25.");
+    assert_eq!(intro.dialog_lines()[0].tags()[0].raw(), "urgent");
+    assert_eq!(intro.dialog_lines()[0].speaker(), None);
+    assert_eq!(intro.statements()[0].before_line(), 1);
+    assert_eq!(intro.divert().unwrap().target(), Some("london"));
+    assert!(intro.choices().is_empty());
+
+    let london = story.knot("london").unwrap();
+    assert_eq!(london.divert(), None);
+    let texts: Vec<&str> = london.choices().iter().map(Choice::text).collect();
+    assert_eq!(texts, vec!["Hail a cab", "Walk"]);
+    assert_eq!(**london.choices()[1].divert(), Divert::Done);
+    assert_eq!(
+        london.choices()[0].dialog_lines()[0].text(),
+        "Passepartout: To the station!"
+    );
+}
+
+#[test]
+fn test_visitors() {
+    let mut story = parse_story(
+        "VAR gold = 10
+EXTERNAL haggle(price, mood)
+One two.
+~ gold -= 1
+Three. #tag
+-> market
+
+=== market
++ Buy
+    ~ gold = gold - 5
+    Four five six.
+    -> END
+
+=== function half(amount)
+~ return amount / 2
+",
+    );
+
+    #[derive(Default)]
+    struct Outline(Vec<String>);
+
+    impl<'ast> Visit<'ast> for Outline {
+        fn visit_external(&mut self, name: &'ast str, parameters: &'ast [String]) {
+            self.0.push(format!("external {}", name));
+            visit::walk_external(self, name, parameters);
+        }
+
+        fn visit_parameter(&mut self, name: &'ast str) {
+            self.0.push(format!("parameter {}", name));
+        }
+
+        fn visit_knot(&mut self, knot: &'ast Knot) {
+            self.0.push(format!("knot {}", knot.title()));
+            visit::walk_knot(self, knot);
+        }
+
+        fn visit_choice(&mut self, choice: &'ast Choice) {
+            self.0.push(format!("choice {}", choice.text()));
+            visit::walk_choice(self, choice);
+        }
+
+        fn visit_dialog_line(&mut self, line: &'ast DialogLine) {
+            self.0.push(format!("line {}", line.text()));
+            visit::walk_dialog_line(self, line);
+        }
+
+        fn visit_statement(&mut self, _statement: &'ast Statement) {
+            self.0.push("statement".to_string());
+        }
+
+        fn visit_divert(&mut self, divert: &'ast Spanned<Divert>) {
+            self.0.push(format!("divert {:?}", divert.node()));
+        }
+
+        fn visit_tag(&mut self, tag: &'ast Tag) {
+            self.0.push(format!("tag {}", tag.raw()));
+        }
+    }
+
+    let mut outline = Outline::default();
+    outline.visit_story(&story);
+    assert_eq!(
+        outline.0,
+        vec![
+            "external haggle",
+            "parameter price",
+            "parameter mood",
+            "knot INTRO",
+            "line One two.",
+            "statement",
+            "line Three.",
+            "tag tag",
+            "divert Knot(\"market\")",
+            "knot market",
+            "choice Buy",
+            "statement",
+            "line Four five six.",
+            "divert End",
+            "knot half",
+            "parameter amount",
+            "statement",
+        ]
+    );
+
+    struct Rename;
+
+    impl VisitMut for Rename {
+        fn visit_parameter_mut(&mut self, name: &mut String) {
+            name.make_ascii_uppercase();
+        }
+
+        fn visit_expression_mut(&mut self, expression: &mut Expression) {
+            if *expression == Expression::Variable("gold".into()) {
+                *expression = Expression::Variable("coins".into());
+            }
+            visit::walk_expression_mut(self, expression);
+        }
+    }
+
+    struct Variables(Vec<String>);
+
+    impl<'ast> Visit<'ast> for Variables {
+        fn visit_expression(&mut self, expression: &'ast Expression) {
+            if let Expression::Variable(name) = expression {
+                self.0.push(name.clone());
+            }
+            visit::walk_expression(self, expression);
+        }
+    }
+
+    Rename.visit_story_mut(&mut story);
+    let mut variables = Variables(vec![]);
+    variables.visit_story(&story);
+    assert_eq!(variables.0, vec!["coins", "amount"]);
+    assert_eq!(story.externals()["haggle"], vec!["PRICE", "MOOD"]);
+    assert_eq!(story.function("half").unwrap().parameters(), ["AMOUNT"]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    let text = "# title: Paris
+VAR health = 3
+Hello.
+~ health -= 1
++ Go
+    -> paris
+
+=== paris
+Bonjour! #greeting
+-> END
+";
+    let story = parse_story(text);
+    let json = serde_json::to_string(&story).unwrap();
+    let round_trip: Story = serde_json::from_str(&json).unwrap();
+    assert_eq!(round_trip, story);
+    assert_eq!(
+        round_trip.knot("paris").unwrap().span().start(),
+        text.find("=== paris").unwrap()
+    );
+
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["version"], FORMAT_VERSION);
+
+    // the example from the docs of `FORMAT_VERSION`
+    let hand_written = r#"{
+      "version": 2,
+      "global_tags": [{ "raw": "title: Paris", "key": "title", "value": "Paris" }],
+      "variables": { "health": { "Int": 3 } },
+      "externals": { "play_sound": ["name"] },
+      "knots": [
+        {
+          "title": "INTRO",
+          "parameters": [],
+          "is_function": false,
+          "tags": [],
+          "dialog_lines": [
+            { "text": "Hello.", "tags": [], "speaker": null, "speaker_prefix": null }
+          ],
+          "statements": [
+            { "before_line": 1, "kind": { "SubtractAssignment": ["health", { "Int": 1 }] } }
+          ],
+          "ending": { "CHOICES": [
+            {
+              "text": "Go",
+              "dialog_lines": [],
+              "statements": [],
+              "divert": { "Knot": "paris" }
+            }
+          ] }
+        }
+      ]
+    }"#;
+    let from_docs: Story = serde_json::from_str(hand_written).unwrap();
+    assert_eq!(
+        from_docs.knots()[0],
+        story.knots()[0].clone().without_spans()
+    );
+
+    assert_eq!(
+        from_docs.externals(),
+        &btreemap! {"play_sound".to_string() => vec!["name".to_string()]}
+    );
+
+    // diverts used to be wrapped in a `node`
+    let version_1 = hand_written.replacen("\"version\": 2", "\"version\": 1", 1);
+    let error = serde_json::from_str::<Story>(&version_1).unwrap_err();
+    assert!(error.to_string().contains("version 1"), "{}", error);
+    assert_eq!(
+        serde_json::to_value(Spanned::from(Divert::End)).unwrap(),
+        serde_json::json!({ "End": null, "span": { "file": 0, "start": 0, "end": 0 } })
+    );
+
+    let future = json.replacen("\"version\":2", "\"version\":3", 1);
+    assert_ne!(future, json);
+    let error = serde_json::from_str::<Story>(&future).unwrap_err();
+    assert!(error.to_string().contains("version 3"), "{}", error);
+}
+
+/// The stories with golden ink JSON in `stories/`
+#[cfg(test)]
+const GOLDEN_STORIES: [&str; 3] = ["two_knots", "basic_story", "around_the_world"];
+
+/// The goldens are this compiler's own output, so this only catches changes to it.
+/// `test_compiled_json_plays_in_inkjs` checks that the output is ink that inkle's
+/// runtime can play.
+#[test]
+fn test_compile_to_ink_json() {
+    for name in GOLDEN_STORIES {
+        let text = std::fs::read_to_string(format!("stories/{}.ink", name)).unwrap();
+        let compiled: serde_json::Value =
+            serde_json::from_str(&compile_story(&parse_story(&text))).unwrap();
+        let golden: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(format!("stories/{}.ink.json", name)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(compiled, golden, "{}", name);
+    }
+
+    // every knot that's diverted to has a container, the top of the story included,
+    // and the knots TURNS_SINCE asks about count turns
+    fn diverts<'a>(json: &'a serde_json::Value, targets: &mut Vec<&'a str>) {
+        match json {
+            serde_json::Value::Array(items) => items.iter().for_each(|item| diverts(item, targets)),
+            serde_json::Value::Object(object) => {
+                if let (Some(target), None) = (object.get("->"), object.get("var")) {
+                    targets.extend(target.as_str());
+                }
+                object.values().for_each(|value| diverts(value, targets));
+            }
+            _ => {}
+        }
+    }
+    let story = parse_story(
+        "Hello.\n+ Again\n    -> INTRO\n+ Wait\n    -> wait\n=== wait\n{TURNS_SINCE(-> INTRO)} {INTRO}\n-> END\n",
+    );
+    let compiled: serde_json::Value = serde_json::from_str(&compile_story(&story)).unwrap();
+    let named = &compiled["root"][2];
+    let mut targets = vec![];
+    diverts(&compiled, &mut targets);
+    assert_eq!(targets, vec!["INTRO", "INTRO", "wait"]);
+    assert!(targets.iter().all(|target| named.get(target).is_some()));
+    let flags = |knot: &str| named[knot].as_array().unwrap().last().unwrap()["#f"].clone();
+    assert_eq!(flags("INTRO"), 3);
+    assert_eq!(flags("wait"), 1);
+}
+
+/// Plays each golden story in inkjs, inkle's JavaScript runtime, always taking the
+/// first choice, and checks that it says the same as `StoryRunner`. It needs `node`,
+/// and inkjs (`npm install inkjs` here, or `INKJS` set to its `dist/ink-full.js`), so
+/// it's left out of a plain `cargo test`:
+///
+/// ```text
+/// INKJS=/path/to/inkjs/dist/ink-full.js cargo test -- --ignored inkjs
+/// ```
+#[test]
+#[ignore]
+fn test_compiled_json_plays_in_inkjs() {
+    const PLAYER: &str = r#"
+const { Story } = require(process.env.INKJS || "inkjs");
+const story = new Story(require("fs").readFileSync(process.argv[1], "utf8"));
+const lines = [];
+while (lines.length < 100) {
+    while (story.canContinue) lines.push(story.Continue().trim());
+    if (story.currentChoices.length === 0) break;
+    story.ChooseChoiceIndex(0);
+}
+console.log(JSON.stringify(lines));
+"#;
+
+    for name in GOLDEN_STORIES {
+        let story = parse_story(&std::fs::read_to_string(format!("stories/{}.ink", name)).unwrap());
+        let compiled = std::env::temp_dir().join(format!("ink_parser_{}.ink.json", name));
+        std::fs::write(&compiled, compile_story(&story)).unwrap();
+
+        let output = std::process::Command::new("node")
+            .arg("-e")
+            .arg(PLAYER)
+            .arg(&compiled)
+            .output()
+            .expect("node should be installed");
+        assert!(
+            output.status.success(),
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let played: Vec<String> = serde_json::from_slice(&output.stdout).unwrap();
+
+        let mut runner = StoryRunner::new(&story);
+        let mut lines = vec![];
+        while lines.len() < 100 {
+            while runner.can_continue() {
+                lines.push(runner.continue_line().unwrap().text().trim().to_string());
+            }
+            if runner.current_choices().is_empty() {
+                break;
+            }
+            runner.choose(0).unwrap();
+        }
+        assert_eq!(played, lines, "{}", name);
+    }
+}
+
+#[test]
+fn test_load_ink_json() {
+    for name in ["two_knots", "basic_story"] {
+        let text = std::fs::read_to_string(format!("stories/{}.ink", name)).unwrap();
+        let story = parse_story(&text);
+        let (loaded, unrepresentable) = load_ink_json(&compile_story(&story)).unwrap();
+        assert_eq!(unrepresentable, vec![], "{}", name);
+        let loaded = loaded.without_spans();
+        assert_eq!(loaded.knots(), story.without_spans().knots(), "{}", name);
+    }
+
+    let (story, unrepresentable) =
+        load_ink_json(include_str!("../stories/around_the_world.ink.json")).unwrap();
+    assert_eq!(unrepresentable, vec![]);
+    assert_eq!(
+        story.global_tag("title").unwrap().value(),
+        Some("Around the World")
+    );
+    assert_eq!(
+        story.variables().get("destination"),
+        Some(&Expression::DivertTarget("paris".into()))
+    );
+    let intro = story.knot("INTRO").unwrap();
+    assert_eq!(
+        intro.dialog_lines()[1].text(),
+        "We have {days} days. Health: {health}."
+    );
+    assert_eq!(
+        intro.choices()[0].statements()[0].kind(),
+        &StatementKind::SubtractAssignment("health".into(), Expression::Int(1))
+    );
+    assert_eq!(
+        **intro.choices()[0].divert(),
+        Divert::Variable("destination".into())
+    );
+    let paris = story.knot("paris").unwrap();
+    assert_eq!(paris.tag("chapter").unwrap().value(), Some("2"));
+    assert_eq!(
+        paris.dialog_lines()[0].text(),
+        "Paris! You've been here {paris} times, after {TURNS()} turns."
+    );
+    assert_eq!(
+        paris.statements()[0].kind(),
+        &StatementKind::AddAssignment("health".into(), Expression::Int(2))
+    );
+
+    // roughly what inklecate writes for `* Hello <> -> there`, then a gather
+    let (story, unrepresentable) = load_ink_json(
+        r##"{"inkVersion":21,"root":[[["ev",{"^->":"0.0.c-0.$r1"},{"temp=":"$r"},"str",{"->":".^.s"},[{"#n":"$r1"}],"/str","/ev",{"*":"0.0.c-0","flg":18},{"s":["^Hello",{"->":"$r","var":true},null],"c-0":["ev",{"^->":"0.0.c-0.$r2"},"/ev",{"->":".^.^.s"},[{"#n":"$r2"}],"<>","\n",{"->":"there"},{"#f":5}]}],null],"done",{"there":["^There.","\n","end",{"#f":1}]}],"listDefs":{}}"##,
+    )
+    .unwrap();
+    let choice = &story.knot("INTRO").unwrap().choices()[0];
+    assert_eq!(choice.text(), "Hello");
+    assert!(choice.dialog_lines().is_empty());
+    assert_eq!(**choice.divert(), Divert::Knot("there".into()));
+    assert_eq!(
+        unrepresentable
+            .iter()
+            .map(|u| (u.path(), u.construct()))
+            .collect::<Vec<_>>(),
+        vec![("0.0", "once-only choice"), ("0.0.c-0", "glue")]
+    );
+
+    // inside a knot, inklecate points to the choice's container relative to the choice
+    let (story, unrepresentable) = load_ink_json(
+        r##"{"inkVersion":21,"root":[[{"->":"shop"},null],"done",{"shop":[["ev","str","^Buy","/str","/ev",{"*":".^.c-0","flg":20},{"c-0":["\n",{"->":"there"},{"#f":5}]}],{"#f":1}],"there":["^There.","\n","end",{"#f":1}]}],"listDefs":{}}"##,
+    )
+    .unwrap();
+    let choice = &story.knot("shop").unwrap().choices()[0];
+    assert_eq!(choice.text(), "Buy");
+    assert_eq!(**choice.divert(), Divert::Knot("there".into()));
+    assert_eq!(
+        unrepresentable
+            .iter()
+            .map(|u| (u.path(), u.construct()))
+            .collect::<Vec<_>>(),
+        vec![("shop.0", "once-only choice")]
+    );
+
+    // a call takes as many arguments as the function has parameters
+    let story = parse_story(
+        "VAR y = 1\n~ temp z = y + double(2)\n{z}\n-> END\n=== function double(x)\n~ return x * 2\n",
+    );
+    let (loaded, unrepresentable) = load_ink_json(&compile_story(&story)).unwrap();
+    assert_eq!(unrepresentable, vec![]);
+    assert_eq!(
+        loaded.knot("INTRO").unwrap().statements()[0].kind(),
+        story.knot("INTRO").unwrap().statements()[0].kind()
+    );
+
+    // and whatever had nothing to work on is reported, not just dropped
+    let (_, unrepresentable) = load_ink_json(
+        r#"{"inkVersion":21,"root":[["ev",1,"+","/ev",{"temp=":"z","re":true},"done",null],"done",null],"listDefs":{}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        unrepresentable
+            .iter()
+            .map(|u| (u.path(), u.construct()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("0", "+ without a value"),
+            ("0", "assignment without a value")
+        ]
+    );
+
+    assert_eq!(
+        load_ink_json(r#"{"inkVersion":5,"root":[]}"#),
+        Err(LoadError::UnsupportedInkVersion(5))
+    );
+}
+
+#[test]
+fn test_story_runner() {
+    let story = parse_story(
+        r#"VAR gold = 2
+~ temp price = 3
+You have {gold} gold. #mood: poor
+{gold < price: You can't afford it.|Sold!}
++ Work
+    ~ gold += 5
+    -> market
++ Leave
+    -> END
+
+=== market
+Shopkeeper: Back with {gold} gold?
+-> END
+"#,
+    );
+    let mut runner = StoryRunner::new(&story);
+
+    let line = runner.continue_line().unwrap();
+    assert_eq!(line.text(), "You have 2 gold.");
+    assert_eq!(line.tag("mood").unwrap().value(), Some("poor"));
+    assert!(runner.current_choices().is_empty());
+    assert_eq!(
+        runner.continue_line().unwrap().text(),
+        "You can't afford it."
+    );
+    assert!(!runner.can_continue());
+    assert_eq!(runner.current_choices(), vec!["Work", "Leave"]);
+
+    assert_eq!(runner.choose(2), Err(RuntimeError::NoSuchChoice(2)));
+    runner.choose(0).unwrap();
+    assert_eq!(runner.continue_line().unwrap().text(), "Work");
+    assert_eq!(
+        runner.continue_line().unwrap().text(),
+        "Shopkeeper: Back with 7 gold?"
+    );
+    assert_eq!(runner.variable("gold"), Some(&Value::Int(7)));
+    assert!(!runner.can_continue());
+    assert!(runner.current_choices().is_empty());
+    assert_eq!(runner.continue_line(), Err(RuntimeError::CannotContinue));
+
+    let story = parse_story("-> nowhere\n");
+    let mut runner = StoryRunner::new(&story);
+    assert!(runner.can_continue());
+    assert_eq!(
+        runner.continue_line(),
+        Err(RuntimeError::UnknownKnot("nowhere".into()))
+    );
+
+    // logic after the last line runs along with it
+    let story = parse_story("VAR x = 0\nHello.\n~ x = 1\n-> END\n");
+    let mut runner = StoryRunner::new(&story);
+    assert_eq!(runner.continue_line().unwrap().text(), "Hello.");
+    assert_eq!(runner.variable("x"), Some(&Value::Int(1)));
+    assert!(!runner.can_continue());
+
+    // logic with no line after it gives an empty one, wherever the flow starts
+    let story = parse_story(
+        "VAR x = 0\n~ x = 1\n+ Go\n    -> END\n=== shop\n~ temp price = 2\n~ x = price\n-> END\n",
+    );
+    let mut runner = StoryRunner::new(&story);
+    assert!(runner.can_continue());
+    assert_eq!(runner.continue_line().unwrap().text(), "");
+    assert!(!runner.can_continue());
+    assert_eq!(runner.current_choices(), vec!["Go"]);
+    assert_eq!(runner.variable("x"), Some(&Value::Int(1)));
+
+    runner.choose_path("shop", &[]).unwrap();
+    assert!(runner.can_continue());
+    assert_eq!(runner.continue_line().unwrap().text(), "");
+    assert!(!runner.can_continue());
+    assert_eq!(runner.variable("x"), Some(&Value::Int(2)));
+
+    runner.switch_flow("other");
+    assert!(runner.can_continue());
+    assert_eq!(runner.continue_line().unwrap().text(), "");
+    assert!(!runner.can_continue());
+    assert_eq!(runner.current_choices(), vec!["Go"]);
+
+    // a line that fails stops the flow, rather than leaving an empty line behind it
+    let story = parse_story("{RANDOM(1, 0)}\n");
+    let mut runner = StoryRunner::new(&story);
+    assert!(runner.continue_line().is_err());
+    assert!(!runner.can_continue());
+    assert_eq!(runner.continue_line(), Err(RuntimeError::CannotContinue));
+
+    // there's no tunnel to return along
+    let story = parse_story("Hi\n-> knot\n=== knot\nIn knot\n->->\n");
+    let mut runner = StoryRunner::new(&story);
+    assert_eq!(runner.continue_line().unwrap().text(), "Hi");
+    assert_eq!(runner.continue_line().unwrap().text(), "In knot");
+    assert_eq!(
+        runner.continue_line(),
+        Err(RuntimeError::InvalidOperation(
+            "->-> without a tunnel".into()
+        ))
+    );
+
+    // speakers stay apart from what they say
+    let mut story = parse_story("# characters: Fogg\nFogg: We leave at once.\n-> END\n");
+    story.extract_speakers(&SpeakerSyntax::default());
+    let mut runner = StoryRunner::new(&story);
+    let line = runner.continue_line().unwrap();
+    assert_eq!(line.text(), "We leave at once.");
+    assert_eq!(line.speaker(), Some("Fogg"));
+}
+
+#[test]
+fn test_visit_counts_and_turns() {
+    let story = parse_story(include_str!("../stories/around_the_world.ink"));
+    let mut runner = StoryRunner::new(&story);
+    assert_eq!(runner.visit_count("INTRO"), Some(1));
+    assert_eq!(runner.visit_count("paris"), Some(0));
+    assert_eq!(runner.visit_count("tokyo"), None);
+    assert_eq!(runner.turns_since("paris"), None);
+
+    runner.continue_line().unwrap();
+    runner.continue_line().unwrap();
+    runner.choose(0).unwrap();
+    assert_eq!(runner.turn_index(), 1);
+    assert_eq!(runner.continue_line().unwrap().text(), "Leave at once");
+    assert_eq!(
+        runner.continue_line().unwrap().text(),
+        "Paris! You've been here 1 times, after 1 turns."
+    );
+    assert_eq!(runner.visit_count("paris"), Some(1));
+    assert_eq!(runner.turns_since("paris"), Some(0));
+    assert_eq!(runner.turns_since("INTRO"), Some(1));
+
+    let story = parse_story(
+        r#"{TURNS_SINCE(-> there)} {READ_COUNT(-> INTRO)} {CHOICE_COUNT()}
++ Choice {CHOICE_COUNT()}
+    -> there
++ Choice {CHOICE_COUNT()}
+    -> there
+
+=== there
+{TURNS_SINCE(-> there)} {there} {TURNS()}
+-> END
+"#,
+    );
+    let mut runner = StoryRunner::new(&story);
+    assert_eq!(runner.continue_line().unwrap().text(), "-1 1 0");
+    assert_eq!(runner.current_choices(), vec!["Choice 0", "Choice 1"]);
+    runner.choose(1).unwrap();
+    runner.continue_line().unwrap();
+    assert_eq!(runner.continue_line().unwrap().text(), "0 1 1");
+}
+
+#[test]
+fn test_save_and_restore_state() {
+    let story = parse_story(include_str!("../stories/around_the_world.ink"));
+    let mut runner = StoryRunner::new(&story);
+    runner.continue_line().unwrap();
+    let saved = runner.save_state();
+
+    let mut transcript = vec![];
+    while runner.can_continue() {
+        transcript.push(runner.continue_line().unwrap().text().to_string());
+    }
+    runner.choose(1).unwrap();
+    for _ in 0..3 {
+        runner.continue_line().unwrap();
+    }
+
+    let mut restored = StoryRunner::new(&story);
+    restored.restore_state(&saved).unwrap();
+    let mut restored_transcript = vec![];
+    while restored.can_continue() {
+        restored_transcript.push(restored.continue_line().unwrap().text().to_string());
+    }
+    assert_eq!(restored_transcript, transcript);
+    assert_eq!(
+        restored.current_choices(),
+        vec!["Leave at once", "\"Around the world, Monsieur?\""]
+    );
+    restored.choose(0).unwrap();
+    while restored.can_continue() {
+        restored.continue_line().unwrap();
+    }
+    assert_eq!(restored.variable("health"), Some(&Value::Int(4)));
+    assert_eq!(restored.visit_count("paris"), Some(1));
+    assert_eq!(restored.turn_index(), 1);
+
+    // a save made at paris's choices
+    let saved = runner.save_state();
+    let mut restored = StoryRunner::new(&story);
+    restored.restore_state(&saved).unwrap();
+    assert_eq!(restored.save_state(), saved);
+
+    let changed = parse_story("Paris is gone.\n");
+    let mut restored = StoryRunner::new(&changed);
+    assert_eq!(
+        restored.restore_state(&saved),
+        Err(RestoreError::IncompatibleStory(
+            "there's no variable called destination".into()
+        ))
+    );
+    let changed = parse_story(
+        "VAR health = 3\nVAR destination = -> paris\nLONDON\n-> paris\n=== paris\n-> END\n",
+    );
+    let mut restored = StoryRunner::new(&changed);
+    assert_eq!(
+        restored.restore_state(&saved),
+        Err(RestoreError::IncompatibleStory(
+            "paris has fewer choices".into()
+        ))
+    );
+    assert_eq!(restored.visit_count("INTRO"), Some(1));
+
+    assert_eq!(
+        restored.restore_state(r#"{"version":99}"#),
+        Err(RestoreError::UnsupportedVersion(99))
+    );
+    assert!(matches!(
+        restored.restore_state("not json"),
+        Err(RestoreError::Malformed(_))
+    ));
+
+    // a visit on a turn that hasn't happened yet
+    let grown = parse_story("VAR coins = 1\nVAR gems = 5\nHello.\n");
+    let saved = StoryRunner::new(&parse_story("VAR coins = 1\nHello.\n")).save_state();
+    let future = saved.replacen(
+        "\"visited_on_turn\":{\"INTRO\":0}",
+        "\"visited_on_turn\":{\"INTRO\":5}",
+        1,
+    );
+    assert_ne!(future, saved);
+    assert_eq!(
+        StoryRunner::new(&grown).restore_state(&future),
+        Err(RestoreError::Malformed("bad visited_on_turn".into()))
+    );
+
+    // a variable added since then starts with its initial value,
+    // and observers hear about the ones that change
+    let mut changes = vec![];
+    let mut runner = StoryRunner::new(&grown);
+    runner.set_variable("gems", Value::Int(9)).unwrap();
+    runner.set_variable("coins", Value::Int(2)).unwrap();
+    runner
+        .observe_variable("coins", |_, old, new| {
+            changes.push((old.clone(), new.clone()))
+        })
+        .unwrap();
+    runner.restore_state(&saved).unwrap();
+    assert_eq!(runner.variable("gems"), Some(&Value::Int(5)));
+    assert_eq!(runner.turns_since("INTRO"), Some(0));
+    drop(runner);
+    assert_eq!(changes, vec![(Value::Int(2), Value::Int(1))]);
+
+    // nothing runs for a new variable's initial value if the save doesn't fit
+    let calls = std::cell::Cell::new(0);
+    let grown = parse_story("EXTERNAL roll()\nVAR coins = 1\nVAR gems = roll()\nHello.\n");
+    let mut runner = StoryRunner::new(&grown);
+    runner.bind_external("roll", || {
+        calls.set(calls.get() + 1);
+        4
+    });
+    let unseeded = saved.replacen("\"random\":", "\"random\":-", 1);
+    assert_ne!(unseeded, saved);
+    assert_eq!(
+        runner.restore_state(&unseeded),
+        Err(RestoreError::Malformed("bad random".into()))
+    );
+    assert_eq!(calls.get(), 0);
+    runner.restore_state(&saved).unwrap();
+    assert_eq!(calls.get(), 1);
+    assert_eq!(runner.variable("gems"), Some(&Value::Int(4)));
+    drop(runner);
+
+    // new variables start from the globals that were saved, in whatever order works
+    let old_story = parse_story("VAR x = 1\nHello.\n");
+    let mut old = StoryRunner::new(&old_story);
+    old.set_variable("x", Value::Int(10)).unwrap();
+    let saved = old.save_state();
+    let grown = parse_story("VAR x = 1\nVAR a = y * 2\nVAR y = x + 1\nHello.\n");
+    let mut runner = StoryRunner::new(&grown);
+    runner.restore_state(&saved).unwrap();
+    assert_eq!(runner.variable("y"), Some(&Value::Int(11)));
+    assert_eq!(runner.variable("a"), Some(&Value::Int(22)));
+
+    // and if one of them can't start, the save doesn't fit
+    let broken = parse_story("VAR x = 1\nVAR y = nowhere()\nHello.\n");
+    let mut runner = StoryRunner::new(&broken);
+    runner.set_variable("x", Value::Int(3)).unwrap();
+    assert_eq!(
+        runner.restore_state(&saved),
+        Err(RestoreError::IncompatibleStory(
+            "y can't start with its initial value: there's no function called nowhere".into()
+        ))
+    );
+    assert_eq!(runner.variable("x"), Some(&Value::Int(3)));
+}
+
+#[test]
+fn test_random_and_alternatives() {
+    let story = parse_story(
+        r#"{RANDOM(1, 6)} {~heads|tails} {a|b} {&x|y} {!once}
++ Again
+    -> INTRO
+"#,
+    );
+    let play = |seed| {
+        let mut runner = StoryRunner::new(&story);
+        runner.set_seed(seed);
+        let mut transcript = vec![];
+        for _ in 0..4 {
+            transcript.push(runner.continue_line().unwrap().text().to_string());
+            runner.choose(0).unwrap();
+            runner.continue_line().unwrap();
+        }
+        transcript
+    };
+    let transcript = play(7);
+    assert_eq!(transcript, play(7));
+    assert_ne!(transcript, play(8));
+    let endings: Vec<_> = transcript
+        .iter()
+        .map(|line| line.splitn(3, ' ').nth(2).unwrap())
+        .collect();
+    assert_eq!(endings, vec!["a x once", "b y", "b x", "b y"]);
+    let mut flips = vec![];
+    for line in &transcript {
+        let mut words = line.split(' ');
+        let roll: i32 = words.next().unwrap().parse().unwrap();
+        assert!((1..=6).contains(&roll), "{}", line);
+        flips.push(words.next().unwrap());
+    }
+    // a shuffle shows everything in it once before it starts over
+    for cycle in flips.chunks(2) {
+        let mut cycle = cycle.to_vec();
+        cycle.sort_unstable();
+        assert_eq!(cycle, vec!["heads", "tails"], "{:?}", flips);
+    }
+
+    let story = parse_story("{~a|b|c}\n+ Again\n    -> INTRO\n");
+    for seed in 0..20 {
+        let mut runner = StoryRunner::new(&story);
+        runner.set_seed(seed);
+        let mut shown = vec![];
+        for _ in 0..9 {
+            shown.push(runner.continue_line().unwrap().text().to_string());
+            runner.choose(0).unwrap();
+            runner.continue_line().unwrap();
+        }
+        for cycle in shown.chunks(3) {
+            let mut cycle = cycle.to_vec();
+            cycle.sort_unstable();
+            assert_eq!(cycle, vec!["a", "b", "c"], "seed {}: {:?}", seed, shown);
+        }
+    }
+
+    // the random numbers carry on after a save
+    let story = parse_story("{RANDOM(1, 1000)}\n{RANDOM(1, 1000)}\n-> END\n");
+    let mut runner = StoryRunner::new(&story);
+    runner.set_seed(1);
+    let saved = runner.save_state();
+    let first = runner.continue_line().unwrap();
+    let mut restored = StoryRunner::new(&story);
+    restored.restore_state(&saved).unwrap();
+    assert_eq!(restored.continue_line().unwrap(), first);
+
+    let story = parse_story("~ SEED_RANDOM(3)\n{RANDOM(1, 1000)}\n-> END\n");
+    let rolls: Vec<_> = (0..2)
+        .map(|_| StoryRunner::new(&story).continue_line().unwrap())
+        .collect();
+    assert_eq!(rolls[0], rolls[1]);
+}
+
+#[test]
+fn test_external_functions() {
+    let story = parse_story(
+        r#"EXTERNAL play_sound(name)
+EXTERNAL gold()
+VAR played = 0
+~ play_sound("bell")
+You have {gold()} gold, and {double(gold())} if you sell the horse.
+-> END
+
+=== function play_sound(name) ===
+~ played += 1
+Ding! #sfx
+
+=== function double(x) ===
+~ return x * 2
+"#,
+    );
+    assert_eq!(
+        story.externals(),
+        &btreemap! {
+            "play_sound".to_string() => vec!["name".to_string()],
+            "gold".to_string() => vec![],
+        }
+    );
+    let double = story.function("double").unwrap();
+    assert!(double.is_function());
+    assert_eq!(double.parameters(), &["x".to_string()]);
+    assert_eq!(
+        double.statements()[0].kind(),
+        &StatementKind::Return(Some(parse_expression().parse("x * 2").unwrap().0))
+    );
+    assert!(story_warnings(&story).is_empty());
+
+    // nothing bound, so play_sound falls back to ink, and gold can't be called
+    let mut runner = StoryRunner::new(&story);
+    let line = runner.continue_line().unwrap();
+    assert_eq!(line.text(), "Ding!");
+    assert!(line.tag("sfx").is_some());
+    assert_eq!(runner.variable("played"), Some(&Value::Int(1)));
+    assert_eq!(
+        runner.continue_line(),
+        Err(RuntimeError::UnknownFunction("gold".into()))
+    );
+
+    let mut sounds = vec![];
+    {
+        let mut runner = StoryRunner::new(&story);
+        runner.bind_external("play_sound", |name: String| sounds.push(name));
+        runner.bind_external("gold", || 21);
+        assert_eq!(
+            runner.continue_line().unwrap().text(),
+            "You have 21 gold, and 42 if you sell the horse."
+        );
+        assert_eq!(runner.variable("played"), Some(&Value::Int(0)));
+    }
+    assert_eq!(sounds, vec!["bell"]);
+
+    let mut runner = StoryRunner::new(&story);
+    runner.bind_external("play_sound", |_: i32| ());
+    assert_eq!(
+        runner.continue_line(),
+        Err(RuntimeError::InvalidOperation(
+            "play_sound(): can't use Str(\"bell\") here".into()
+        ))
+    );
+
+    let mut runner = StoryRunner::new(&story);
+    runner.bind_external("gold", |_: i32| 21);
+    runner.continue_line().unwrap();
+    assert_eq!(
+        runner.continue_line(),
+        Err(RuntimeError::InvalidOperation(
+            "gold(): 0 arguments, not 1".into()
+        ))
+    );
+
+    // functions and externals survive being compiled and loaded again
+    let (loaded, unrepresentable) = load_ink_json(&compile_story(&story)).unwrap();
+    assert_eq!(unrepresentable, vec![]);
+    assert_eq!(
+        loaded.function("double").unwrap().clone().without_spans(),
+        double.clone().without_spans()
+    );
+    assert_eq!(
+        loaded.externals(),
+        &btreemap! {
+            "play_sound".to_string() => vec!["arg1".to_string()],
+            "gold".to_string() => vec![],
+        }
+    );
+    let mut runner = StoryRunner::new(&loaded);
+    runner.bind_external("gold", || 2);
+    assert_eq!(runner.continue_line().unwrap().text(), "Ding!");
+    assert_eq!(
+        runner.continue_line().unwrap().text(),
+        "You have 2 gold, and 4 if you sell the horse."
+    );
+}
+
+#[test]
+fn test_variable_observers() {
+    // like story_with_variables.ink
+    let story = parse_story(
+        r#"VAR health = 100
+VAR gold = 0
+LONDON, 1872
+~ temp days = 80
+-> paris_suburbs
+
+=== paris_suburbs
+Suburbs were cool too.
+~ health -= 2
+~ gold = 5
+~ health -= 3
+-> london
+
+=== london ===
+health: "{health}"
+-> END
+"#,
+    );
+    let mut changes = vec![];
+    {
+        let mut runner = StoryRunner::new(&story);
+        runner
+            .observe_variable("health", |name, old, new| {
+                changes.push(format!("{}: {} -> {}", name, old, new))
+            })
+            .unwrap();
+        assert_eq!(
+            runner.observe_variable("days", |_, _, _| {}),
+            Err(RuntimeError::UnknownVariable("days".into()))
+        );
+
+        runner.continue_line().unwrap();
+        runner.continue_line().unwrap();
+        // both assignments come out of this step, as one change
+        assert_eq!(runner.continue_line().unwrap().text(), "health: \"95\"");
+        runner.set_variable("health", Value::Int(50)).unwrap();
+        runner.set_variable("gold", Value::Int(1)).unwrap();
+    }
+    assert_eq!(changes, vec!["health: 100 -> 95", "health: 95 -> 50"]);
+}
+
+#[test]
+fn test_choose_path() {
+    let story = parse_story(
+        r#"LONDON, 1872
++ Leave
+    -> END
+
+=== london(day, weather)
+Day {day} in London, and it's {weather}.
+-> END
+
+=== function greet()
+~ return "Hello"
+"#,
+    );
+    let mut runner = StoryRunner::new(&story);
+    runner.continue_line().unwrap();
+    assert_eq!(runner.current_choices(), vec!["Leave"]);
+
+    runner
+        .choose_path("london", &[Value::Int(2), "raining".into()])
+        .unwrap();
+    assert!(runner.current_choices().is_empty());
+    assert_eq!(
+        runner.continue_line().unwrap().text(),
+        "Day 2 in London, and it's raining."
+    );
+    assert_eq!(runner.visit_count("london"), Some(1));
+    assert_eq!(runner.turn_index(), 0);
+
+    assert_eq!(
+        runner.choose_path("tokyo", &[]),
+        Err(RuntimeError::UnknownKnot("tokyo".into()))
+    );
+    assert_eq!(
+        runner.choose_path("london", &[]),
+        Err(RuntimeError::InvalidOperation(
+            "-> london with 0 arguments, not 2".into()
+        ))
+    );
+    assert!(runner.choose_path("greet", &[]).is_err());
+
+    // stitches are knots titled `knot.stitch`, as loaded from ink's JSON
+    let story = Story::new()
+        .with_knot(Knot::new("INTRO").with_divert(Divert::End))
+        .with_knot(
+            Knot::new("paris.downtown")
+                .with_line("It was cool downtown.".into())
+                .with_divert(Divert::End),
+        );
+    let mut runner = StoryRunner::new(&story);
+    assert!(!runner.can_continue());
+    runner.choose_path("paris.downtown", &[]).unwrap();
+    assert_eq!(
+        runner.continue_line().unwrap().text(),
+        "It was cool downtown."
+    );
+}
+
+#[test]
+fn test_evaluate_from_the_host() {
+    let story = parse_story(
+        r#"VAR health = 80
+VAR name = "Fogg"
+~ temp days = 80
+Hello.
+-> END
+
+=== function describe(x) ===
+{name} is here.
+~ health -= x
+~ return health > 50
+"#,
+    );
+    let mut runner = StoryRunner::new(&story);
+    runner.continue_line().unwrap();
+
+    let result = runner.evaluate_expression("health > 50").unwrap();
+    assert_eq!(result.value(), &Value::Bool(true));
+    assert_eq!(result.output(), "");
+    assert_eq!(
+        runner
+            .evaluate_expression("days * 2 + health")
+            .unwrap()
+            .value(),
+        &Value::Int(240)
+    );
+    assert_eq!(
+        runner
+            .evaluate_expression("describe(10) && describe(25)")
+            .unwrap()
+            .output(),
+        "Fogg is here.\nFogg is here."
+    );
+    assert_eq!(runner.variable("health"), Some(&Value::Int(45)));
+
+    let result = runner
+        .call_function("describe", &[Value::Int(-10)])
+        .unwrap();
+    assert_eq!(result.value(), &Value::Bool(true));
+    assert_eq!(result.output(), "Fogg is here.");
+    assert!(!runner.can_continue());
+
+    assert_eq!(
+        runner.call_function("fly", &[]),
+        Err(RuntimeError::UnknownFunction("fly".into()))
+    );
+    assert_eq!(
+        runner.evaluate_expression("health >"),
+        Err(RuntimeError::InvalidExpression("health >".into()))
+    );
+}
+
+#[test]
+fn test_rewind() {
+    let story = parse_story(
+        r#"VAR coins = 0
+A fork in the road.
++ Left
+    ~ coins += 1
+    -> INTRO
++ Right
+    ~ coins += 10
+    -> INTRO
+"#,
+    );
+    fn play(runner: &mut StoryRunner<'_>, choice: usize) {
+        while runner.can_continue() {
+            runner.continue_line().unwrap();
+        }
+        runner.choose(choice).unwrap();
+    }
+
+    let mut changes = vec![];
+    let mut runner = StoryRunner::new(&story);
+    runner.keep_history(2);
+    play(&mut runner, 0);
+    play(&mut runner, 1);
+    play(&mut runner, 1);
+    runner.continue_line().unwrap();
+    runner.continue_line().unwrap();
+    assert_eq!(runner.variable("coins"), Some(&Value::Int(21)));
+    assert_eq!(runner.visit_count("INTRO"), Some(4));
+    assert_eq!(runner.history_len(), 2);
+    let log: Vec<_> = runner.text_log().iter().map(Line::text).collect();
+    assert_eq!(
+        log,
+        vec![
+            "Left",
+            "A fork in the road.",
+            "Right",
+            "A fork in the road.",
+            "Right",
+            "A fork in the road."
+        ]
+    );
+
+    runner
+        .observe_variable("coins", |_, old, new| {
+            changes.push((old.clone(), new.clone()))
+        })
+        .unwrap();
+    runner.rewind(2).unwrap();
+    drop(runner);
+    assert_eq!(changes, vec![(Value::Int(21), Value::Int(1))]);
+
+    let mut runner = StoryRunner::new(&story);
+    runner.keep_history(2);
+    play(&mut runner, 0);
+    play(&mut runner, 1);
+    runner.rewind(2).unwrap();
+    assert_eq!(runner.variable("coins"), Some(&Value::Int(0)));
+    assert_eq!(runner.visit_count("INTRO"), Some(1));
+    assert_eq!(runner.turn_index(), 0);
+    assert_eq!(runner.current_choices(), vec!["Left", "Right"]);
+    assert_eq!(runner.text_log().len(), 1);
+    assert_eq!(runner.rewind(1), Err(RuntimeError::CannotRewind(1)));
+
+    runner.choose(1).unwrap();
+    assert_eq!(runner.continue_line().unwrap().text(), "Right");
+    assert_eq!(runner.variable("coins"), Some(&Value::Int(10)));
+}
+
+#[test]
+fn test_flows() {
+    let story = parse_story(
+        r#"VAR trust = 0
+Main story.
++ Go on
+    -> END
+=== chat
+~ trust += 1
+Hi there.
++ Wave
+    -> END
+"#,
+    );
+    let mut runner = StoryRunner::new(&story);
+    assert_eq!(runner.current_flow(), DEFAULT_FLOW);
+    assert_eq!(runner.continue_line().unwrap().text(), "Main story.");
+    assert_eq!(runner.current_choices(), vec!["Go on"]);
+
+    runner.switch_flow("chat");
+    assert_eq!(runner.flows(), vec![DEFAULT_FLOW, "chat"]);
+    assert!(runner.current_choices().is_empty());
+    runner.choose_path("chat", &[]).unwrap();
+    assert_eq!(runner.continue_line().unwrap().text(), "Hi there.");
+    assert_eq!(runner.current_choices(), vec!["Wave"]);
+    let saved = runner.save_state();
+
+    runner.switch_flow(DEFAULT_FLOW);
+    assert_eq!(runner.current_choices(), vec!["Go on"]);
+    assert_eq!(runner.variable("trust"), Some(&Value::Int(1)));
+    // chat went straight to its knot, without going into INTRO on the way
+    assert_eq!(runner.visit_count("INTRO"), Some(1));
+
+    // a new flow only goes into the first knot once it's continued
+    runner.switch_flow("aside");
+    assert!(runner.can_continue());
+    assert_eq!(runner.visit_count("INTRO"), Some(1));
+    let saved_aside = runner.save_state();
+    assert_eq!(runner.continue_line().unwrap().text(), "Main story.");
+    assert_eq!(runner.visit_count("INTRO"), Some(2));
+    let mut restored = StoryRunner::new(&story);
+    restored.restore_state(&saved_aside).unwrap();
+    assert_eq!(restored.visit_count("INTRO"), Some(1));
+    assert_eq!(restored.continue_line().unwrap().text(), "Main story.");
+    assert_eq!(restored.visit_count("INTRO"), Some(2));
+    runner.switch_flow(DEFAULT_FLOW);
+    runner.remove_flow("aside").unwrap();
+
+    let mut restored = StoryRunner::new(&story);
+    restored.restore_state(&saved).unwrap();
+    assert_eq!(restored.current_flow(), "chat");
+    assert_eq!(restored.current_choices(), vec!["Wave"]);
+    restored.switch_flow(DEFAULT_FLOW);
+    assert_eq!(restored.current_choices(), vec!["Go on"]);
+
+    runner.switch_flow("chat");
+    runner.remove_flow("chat").unwrap();
+    assert_eq!(runner.current_flow(), DEFAULT_FLOW);
+    assert_eq!(runner.flows(), vec![DEFAULT_FLOW]);
+    assert_eq!(runner.current_choices(), vec!["Go on"]);
+    assert_eq!(
+        runner.remove_flow("chat"),
+        Err(RuntimeError::UnknownFlow("chat".into()))
+    );
+    assert!(runner.remove_flow(DEFAULT_FLOW).is_err());
+
+    // saves from before there were flows
+    let mut restored = StoryRunner::new(&story);
+    restored
+        .restore_state(
+            r#"{"version":1,"globals":{"trust":5},"temps":{},"position":null,
+            "choices":[{"knot":"chat","choice":0,"text":"Wave"}],"choice_text":null,
+            "output":[],"error":null,"visits":{"INTRO":1,"chat":1},"visited_on_turn":{},
+            "turn_index":0,"random":1,"alternatives":{}}"#,
+        )
+        .unwrap();
+    assert_eq!(restored.flows(), vec![DEFAULT_FLOW]);
+    assert_eq!(restored.current_choices(), vec!["Wave"]);
+    assert_eq!(restored.variable("trust"), Some(&Value::Int(5)));
+}
+
+#[test]
+fn test_tag_handlers() {
+    let story = parse_story(
+        r#"Upstairs. #camera wide #cameraman: Lou #mood: calm
+-> cellar
+=== cellar
+# audio: drip
+# camera dark
+It's damp. #audio: splash #camera
+-> END
+"#,
+    );
+    let events = std::cell::RefCell::new(vec![]);
+    let record = |tag: &Tag, source: TagSource<'_>| {
+        let source = match source {
+            TagSource::Line(line) => line.text().to_string(),
+            TagSource::Knot(title) => format!("-> {}", title),
+        };
+        events.borrow_mut().push((tag.raw().to_string(), source));
+    };
+    let mut runner = StoryRunner::new(&story);
+    runner.on_tag("audio:", record);
+    runner.on_tag("camera", record);
+
+    runner.continue_line().unwrap();
+    assert_eq!(
+        events.borrow().clone(),
+        vec![
+            ("camera wide".to_string(), "Upstairs.".to_string()),
+            ("audio: drip".to_string(), "-> cellar".to_string()),
+            ("camera dark".to_string(), "-> cellar".to_string()),
+        ]
+    );
+    events.borrow_mut().clear();
+    runner.continue_line().unwrap();
+    assert_eq!(
+        events.borrow().clone(),
+        vec![
+            ("audio: splash".to_string(), "It's damp.".to_string()),
+            ("camera".to_string(), "It's damp.".to_string()),
+        ]
+    );
+}
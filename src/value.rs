@@ -0,0 +1,180 @@
+use crate::runtime::RuntimeError;
+use crate::{BinaryOperator, UnaryOperator};
+use std::fmt;
+
+/// What an expression evaluates to while a story runs.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    /// `-> paris`, as a value
+    DivertTarget(String),
+}
+
+impl Value {
+    /// Zero, `false` and `""` are false, like in ink.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Int(n) => *n != 0,
+            Value::Float(x) => *x != 0.0,
+            Value::Bool(b) => *b,
+            Value::Str(s) => !s.is_empty(),
+            Value::DivertTarget(_) => true,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Value::Int(n) => Some(*n),
+            Value::Bool(b) => Some(*b as i32),
+            _ => None,
+        }
+    }
+
+    /// Ints (and bools) are widened to floats
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Float(x) => Some(*x),
+            _ => self.as_int().map(f64::from),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn unary(operator: UnaryOperator, operand: Value) -> Result<Value, RuntimeError> {
+        match (operator, operand) {
+            (UnaryOperator::Not, operand) => Ok(Value::Bool(!operand.is_truthy())),
+            (UnaryOperator::Negate, Value::Int(n)) => Ok(Value::Int(n.wrapping_neg())),
+            (UnaryOperator::Negate, Value::Float(x)) => Ok(Value::Float(-x)),
+            (UnaryOperator::Negate, operand) => Err(RuntimeError::InvalidOperation(format!(
+                "can't negate {}",
+                operand
+            ))),
+        }
+    }
+
+    pub(crate) fn binary(
+        left: Value,
+        operator: BinaryOperator,
+        right: Value,
+    ) -> Result<Value, RuntimeError> {
+        use BinaryOperator::*;
+
+        let invalid = |left: &Value, right: &Value| {
+            RuntimeError::InvalidOperation(format!("{:?} {} {:?}", left, operator, right))
+        };
+
+        match operator {
+            Or => return Ok(Value::Bool(left.is_truthy() || right.is_truthy())),
+            And => return Ok(Value::Bool(left.is_truthy() && right.is_truthy())),
+            Equal => return Ok(Value::Bool(left.loosely_equals(&right))),
+            NotEqual => return Ok(Value::Bool(!left.loosely_equals(&right))),
+            Add if left.as_str().is_some() || right.as_str().is_some() => {
+                return Ok(Value::Str(format!("{}{}", left, right)))
+            }
+            _ => {}
+        }
+
+        if let (Some(a), Some(b)) = (left.as_int(), right.as_int()) {
+            return match operator {
+                Add => Ok(Value::Int(a.wrapping_add(b))),
+                Subtract => Ok(Value::Int(a.wrapping_sub(b))),
+                Multiply => Ok(Value::Int(a.wrapping_mul(b))),
+                Divide | Modulo if b == 0 => Err(RuntimeError::InvalidOperation(format!(
+                    "{} {} 0",
+                    a, operator
+                ))),
+                Divide => Ok(Value::Int(a.wrapping_div(b))),
+                Modulo => Ok(Value::Int(a.wrapping_rem(b))),
+                Less => Ok(Value::Bool(a < b)),
+                LessOrEqual => Ok(Value::Bool(a <= b)),
+                Greater => Ok(Value::Bool(a > b)),
+                GreaterOrEqual => Ok(Value::Bool(a >= b)),
+                Or | And | Equal | NotEqual => unreachable!(),
+            };
+        }
+
+        let (a, b) = match (left.as_float(), right.as_float()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(invalid(&left, &right)),
+        };
+        Ok(match operator {
+            Add => Value::Float(a + b),
+            Subtract => Value::Float(a - b),
+            Multiply => Value::Float(a * b),
+            Divide => Value::Float(a / b),
+            Modulo => Value::Float(a % b),
+            Less => Value::Bool(a < b),
+            LessOrEqual => Value::Bool(a <= b),
+            Greater => Value::Bool(a > b),
+            GreaterOrEqual => Value::Bool(a >= b),
+            Or | And | Equal | NotEqual => unreachable!(),
+        })
+    }
+
+    /// Numbers compare by value, whatever their type
+    fn loosely_equals(&self, other: &Value) -> bool {
+        match (self.as_float(), other.as_float()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self == other,
+        }
+    }
+}
+
+/// How a value is printed into the story's text
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => f.write_str(s),
+            Value::DivertTarget(target) => f.write_str(target),
+        }
+    }
+}
+
+/// Nothing, like what a function that doesn't `~ return` anything gives back.
+/// It prints as nothing.
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Str(String::new())
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Float(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
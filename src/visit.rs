@@ -0,0 +1,341 @@
+use crate::{
+    Choice, DialogLine, Divert, Expression, Knot, KnotEnding, Spanned, Statement, StatementKind,
+    Story, Tag,
+};
+
+/// Visits the nodes of a story in the order they were written.
+/// Statements come between the dialog lines they were written between.
+///
+/// Each method walks into the node's children by default, so a pass only overrides the nodes
+/// it cares about, and calls the matching `walk_` function if it wants to keep going.
+pub trait Visit<'ast> {
+    fn visit_story(&mut self, story: &'ast Story) {
+        walk_story(self, story)
+    }
+
+    /// a `VAR` declaration
+    fn visit_variable(&mut self, name: &'ast str, value: &'ast Expression) {
+        walk_variable(self, name, value)
+    }
+
+    /// an `EXTERNAL` declaration
+    fn visit_external(&mut self, name: &'ast str, parameters: &'ast [String]) {
+        walk_external(self, name, parameters)
+    }
+
+    /// a parameter of a knot, function or external
+    fn visit_parameter(&mut self, _name: &'ast str) {}
+
+    fn visit_knot(&mut self, knot: &'ast Knot) {
+        walk_knot(self, knot)
+    }
+
+    fn visit_knot_ending(&mut self, ending: &'ast KnotEnding) {
+        walk_knot_ending(self, ending)
+    }
+
+    fn visit_choice(&mut self, choice: &'ast Choice) {
+        walk_choice(self, choice)
+    }
+
+    fn visit_dialog_line(&mut self, line: &'ast DialogLine) {
+        walk_dialog_line(self, line)
+    }
+
+    fn visit_statement(&mut self, statement: &'ast Statement) {
+        walk_statement(self, statement)
+    }
+
+    fn visit_divert(&mut self, _divert: &'ast Spanned<Divert>) {}
+
+    fn visit_tag(&mut self, _tag: &'ast Tag) {}
+
+    fn visit_expression(&mut self, expression: &'ast Expression) {
+        walk_expression(self, expression)
+    }
+}
+
+pub fn walk_story<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, story: &'ast Story) {
+    for tag in &story.global_tags {
+        visitor.visit_tag(tag);
+    }
+    for (name, value) in &story.variables {
+        visitor.visit_variable(name, value);
+    }
+    for (name, parameters) in &story.externals {
+        visitor.visit_external(name, parameters);
+    }
+    for knot in &story.knots {
+        visitor.visit_knot(knot);
+    }
+}
+
+pub fn walk_variable<'ast, V: Visit<'ast> + ?Sized>(
+    visitor: &mut V,
+    _name: &'ast str,
+    value: &'ast Expression,
+) {
+    visitor.visit_expression(value);
+}
+
+pub fn walk_external<'ast, V: Visit<'ast> + ?Sized>(
+    visitor: &mut V,
+    _name: &'ast str,
+    parameters: &'ast [String],
+) {
+    for parameter in parameters {
+        visitor.visit_parameter(parameter);
+    }
+}
+
+pub fn walk_knot<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, knot: &'ast Knot) {
+    for parameter in &knot.parameters {
+        visitor.visit_parameter(parameter);
+    }
+    for tag in &knot.tags {
+        visitor.visit_tag(tag);
+    }
+    walk_content(visitor, &knot.dialog_lines, &knot.statements);
+    visitor.visit_knot_ending(&knot.ending);
+}
+
+pub fn walk_knot_ending<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, ending: &'ast KnotEnding) {
+    match ending {
+        KnotEnding::CHOICES(choices) => {
+            for choice in choices {
+                visitor.visit_choice(choice);
+            }
+        }
+        KnotEnding::DIVERT(divert) => visitor.visit_divert(divert),
+        KnotEnding::IMPLICIT => {}
+    }
+}
+
+pub fn walk_choice<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, choice: &'ast Choice) {
+    walk_content(visitor, &choice.dialog_lines, &choice.statements);
+    visitor.visit_divert(&choice.divert);
+}
+
+pub fn walk_dialog_line<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, line: &'ast DialogLine) {
+    for tag in &line.tags {
+        visitor.visit_tag(tag);
+    }
+}
+
+pub fn walk_statement<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, statement: &'ast Statement) {
+    match &statement.kind {
+        StatementKind::TempDeclaration(_, value)
+        | StatementKind::Assignment(_, value)
+        | StatementKind::AddAssignment(_, value)
+        | StatementKind::SubtractAssignment(_, value)
+        | StatementKind::Expression(value)
+        | StatementKind::Return(Some(value)) => visitor.visit_expression(value),
+        StatementKind::Return(None) => {}
+    }
+}
+
+pub fn walk_expression<'ast, V: Visit<'ast> + ?Sized>(
+    visitor: &mut V,
+    expression: &'ast Expression,
+) {
+    match expression {
+        Expression::Unary(_, operand) => visitor.visit_expression(operand),
+        Expression::Binary(left, _, right) => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        }
+        Expression::Call(_, arguments) => {
+            for argument in arguments {
+                visitor.visit_expression(argument);
+            }
+        }
+        Expression::Int(_)
+        | Expression::Float(_)
+        | Expression::Bool(_)
+        | Expression::Str(_)
+        | Expression::DivertTarget(_)
+        | Expression::Variable(_) => {}
+    }
+}
+
+/// dialog lines, with each statement just before the line it runs before
+fn walk_content<'ast, V: Visit<'ast> + ?Sized>(
+    visitor: &mut V,
+    dialog_lines: &'ast [DialogLine],
+    statements: &'ast [Statement],
+) {
+    let mut statements = statements.iter().peekable();
+    for (index, line) in dialog_lines.iter().enumerate() {
+        while let Some(statement) = statements.next_if(|s| s.before_line <= index) {
+            visitor.visit_statement(statement);
+        }
+        visitor.visit_dialog_line(line);
+    }
+    for statement in statements {
+        visitor.visit_statement(statement);
+    }
+}
+
+/// Like `Visit`, but each node can be changed in place.
+pub trait VisitMut {
+    fn visit_story_mut(&mut self, story: &mut Story) {
+        walk_story_mut(self, story)
+    }
+
+    /// a `VAR` declaration; only its initial value can change
+    fn visit_variable_mut(&mut self, name: &str, value: &mut Expression) {
+        walk_variable_mut(self, name, value)
+    }
+
+    /// an `EXTERNAL` declaration; only its parameters can change
+    fn visit_external_mut(&mut self, name: &str, parameters: &mut Vec<String>) {
+        walk_external_mut(self, name, parameters)
+    }
+
+    fn visit_parameter_mut(&mut self, _name: &mut String) {}
+
+    fn visit_knot_mut(&mut self, knot: &mut Knot) {
+        walk_knot_mut(self, knot)
+    }
+
+    fn visit_knot_ending_mut(&mut self, ending: &mut KnotEnding) {
+        walk_knot_ending_mut(self, ending)
+    }
+
+    fn visit_choice_mut(&mut self, choice: &mut Choice) {
+        walk_choice_mut(self, choice)
+    }
+
+    fn visit_dialog_line_mut(&mut self, line: &mut DialogLine) {
+        walk_dialog_line_mut(self, line)
+    }
+
+    fn visit_statement_mut(&mut self, statement: &mut Statement) {
+        walk_statement_mut(self, statement)
+    }
+
+    fn visit_divert_mut(&mut self, _divert: &mut Spanned<Divert>) {}
+
+    fn visit_tag_mut(&mut self, _tag: &mut Tag) {}
+
+    fn visit_expression_mut(&mut self, expression: &mut Expression) {
+        walk_expression_mut(self, expression)
+    }
+}
+
+pub fn walk_story_mut<V: VisitMut + ?Sized>(visitor: &mut V, story: &mut Story) {
+    for tag in &mut story.global_tags {
+        visitor.visit_tag_mut(tag);
+    }
+    for (name, value) in &mut story.variables {
+        visitor.visit_variable_mut(name, value);
+    }
+    for (name, parameters) in &mut story.externals {
+        visitor.visit_external_mut(name, parameters);
+    }
+    for knot in &mut story.knots {
+        visitor.visit_knot_mut(knot);
+    }
+}
+
+pub fn walk_variable_mut<V: VisitMut + ?Sized>(
+    visitor: &mut V,
+    _name: &str,
+    value: &mut Expression,
+) {
+    visitor.visit_expression_mut(value);
+}
+
+pub fn walk_external_mut<V: VisitMut + ?Sized>(
+    visitor: &mut V,
+    _name: &str,
+    parameters: &mut Vec<String>,
+) {
+    for parameter in parameters {
+        visitor.visit_parameter_mut(parameter);
+    }
+}
+
+pub fn walk_knot_mut<V: VisitMut + ?Sized>(visitor: &mut V, knot: &mut Knot) {
+    for parameter in &mut knot.parameters {
+        visitor.visit_parameter_mut(parameter);
+    }
+    for tag in &mut knot.tags {
+        visitor.visit_tag_mut(tag);
+    }
+    walk_content_mut(visitor, &mut knot.dialog_lines, &mut knot.statements);
+    visitor.visit_knot_ending_mut(&mut knot.ending);
+}
+
+pub fn walk_knot_ending_mut<V: VisitMut + ?Sized>(visitor: &mut V, ending: &mut KnotEnding) {
+    match ending {
+        KnotEnding::CHOICES(choices) => {
+            for choice in choices {
+                visitor.visit_choice_mut(choice);
+            }
+        }
+        KnotEnding::DIVERT(divert) => visitor.visit_divert_mut(divert),
+        KnotEnding::IMPLICIT => {}
+    }
+}
+
+pub fn walk_choice_mut<V: VisitMut + ?Sized>(visitor: &mut V, choice: &mut Choice) {
+    walk_content_mut(visitor, &mut choice.dialog_lines, &mut choice.statements);
+    visitor.visit_divert_mut(&mut choice.divert);
+}
+
+pub fn walk_dialog_line_mut<V: VisitMut + ?Sized>(visitor: &mut V, line: &mut DialogLine) {
+    for tag in &mut line.tags {
+        visitor.visit_tag_mut(tag);
+    }
+}
+
+pub fn walk_statement_mut<V: VisitMut + ?Sized>(visitor: &mut V, statement: &mut Statement) {
+    match &mut statement.kind {
+        StatementKind::TempDeclaration(_, value)
+        | StatementKind::Assignment(_, value)
+        | StatementKind::AddAssignment(_, value)
+        | StatementKind::SubtractAssignment(_, value)
+        | StatementKind::Expression(value)
+        | StatementKind::Return(Some(value)) => visitor.visit_expression_mut(value),
+        StatementKind::Return(None) => {}
+    }
+}
+
+pub fn walk_expression_mut<V: VisitMut + ?Sized>(visitor: &mut V, expression: &mut Expression) {
+    match expression {
+        Expression::Unary(_, operand) => visitor.visit_expression_mut(operand),
+        Expression::Binary(left, _, right) => {
+            visitor.visit_expression_mut(left);
+            visitor.visit_expression_mut(right);
+        }
+        Expression::Call(_, arguments) => {
+            for argument in arguments {
+                visitor.visit_expression_mut(argument);
+            }
+        }
+        Expression::Int(_)
+        | Expression::Float(_)
+        | Expression::Bool(_)
+        | Expression::Str(_)
+        | Expression::DivertTarget(_)
+        | Expression::Variable(_) => {}
+    }
+}
+
+fn walk_content_mut<V: VisitMut + ?Sized>(
+    visitor: &mut V,
+    dialog_lines: &mut [DialogLine],
+    statements: &mut [Statement],
+) {
+    let mut statements = statements.iter_mut().peekable();
+    for (index, line) in dialog_lines.iter_mut().enumerate() {
+        while let Some(statement) = statements.next_if(|s| s.before_line <= index) {
+            visitor.visit_statement_mut(statement);
+        }
+        visitor.visit_dialog_line_mut(line);
+    }
+    for statement in statements {
+        visitor.visit_statement_mut(statement);
+    }
+}